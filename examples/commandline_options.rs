@@ -8,8 +8,8 @@ fn main() {
     let mut settings = Settings::new();
     settings.opt(optopt("p", "port", "the port to bind to", "4000"));
     settings.opt(optopt("e", "environment", "the environment to run in", ""));
-    let errors = settings.load_os_args();
-    if errors.is_some() {
+    let result = settings.load_os_args();
+    if result.is_err() {
         println!("{}", settings.usage("Try one of these:".to_string()));
     }
 }
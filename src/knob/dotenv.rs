@@ -0,0 +1,36 @@
+//! A parser for `.env` files as read by `Settings::load_dotenv`.
+
+use super::toml::strip_comment;
+
+/// Parse a `.env` file into pairs of keys and values. Returns the offending
+/// line as an error if a line is not a `KEY=value` pair.
+pub fn parse(text: &str) -> Result<Vec<(String, String)>, String> {
+  let mut pairs = Vec::new();
+  for line in text.lines() {
+    let line = strip_comment(line).trim();
+    if line.is_empty() {
+      continue
+    }
+    let line = if line.starts_with("export ") { line.slice_from(7).trim_left() } else { line };
+    match line.find('=') {
+      Some(position) => {
+        let key = line.slice_to(position).trim();
+        let value = unquote(line.slice_from(position + 1).trim());
+        pairs.push((key.to_string(), value.to_string()));
+      },
+      None => { return Err(line.to_string()) }
+    }
+  }
+  Ok(pairs)
+}
+
+/// Remove matching single or double quotes around a value.
+fn unquote<'a>(value: &'a str) -> &'a str {
+  if value.len() >= 2 &&
+     ((value.starts_with("\"") && value.ends_with("\"")) ||
+      (value.starts_with("'") && value.ends_with("'"))) {
+    value.slice(1, value.len() - 1)
+  } else {
+    value
+  }
+}
@@ -74,6 +74,32 @@
 //! }
 //! ~~~
 //!
+//! To control how a key is stored, implement `Key` for it instead of `Show`:
+//!
+//! ~~~
+//! extern crate knob;
+//!
+//! use knob::{Settings, Key};
+//!
+//! enum Keys {
+//!   DatabaseUrl,
+//! }
+//!
+//! impl Key for Keys {
+//!   fn as_key(&self) -> String {
+//!     match *self {
+//!       DatabaseUrl => { "database.url".to_string() }
+//!     }
+//!   }
+//! }
+//!
+//! fn main() {
+//!   let mut settings = Settings::new();
+//!   settings.set(DatabaseUrl, "postgres://localhost/knob");
+//!   assert!(settings.contains("database.url"));
+//! }
+//! ~~~
+//!
 //! # Registering command line options
 //!
 //! `knob` allows you to register command line options to read from the command line later.
@@ -93,8 +119,8 @@
 //!   let mut settings = Settings::new();
 //!   settings.opt(optopt("p", "port", "the port to bind to", "4000"));
 //!   settings.opt(optopt("e", "environment", "the environment to run in", ""));
-//!   let errors = settings.load_os_args();
-//!   if errors.is_some() {
+//!   let result = settings.load_os_args();
+//!   if result.is_err() {
 //!     println!("{}", settings.usage(String::from_str("Try one of these:")));
 //!   }
 //! }
@@ -163,105 +189,2468 @@
 #![license = "MIT"]
 
 extern crate getopts;
+extern crate serialize;
+extern crate sync;
 extern crate debug;
 
-use std::collections::HashMap;
+use std::any::{Any, AnyRefExt};
+use std::ascii::StrAsciiExt;
+use std::collections::{HashMap, TreeMap};
+use std::io::{File, IoError, IoResult, InvalidInput};
+use std::cmp;
+use std::default::Default;
+use std::fmt;
+use std::intrinsics::TypeId;
+use std::iter::FromIterator;
+use std::mem;
+use std::num::{from_str_radix, CheckedMul};
 use std::os;
+use std::slice::Items;
+use std::sync::atomics::{AtomicUint, INIT_ATOMIC_UINT, SeqCst};
+use std::time::Duration;
 use std::from_str::FromStr;
 use std::to_str::ToStr;
 
-use getopts::{usage,getopts,OptGroup};
+use getopts::{usage,getopts,optflag,OptGroup,Matches};
+use getopts::{No, Maybe, Yes, Req, Optional, Multi};
 use getopts::Fail_;
+use serialize::json;
+use sync::Mutex;
+
+mod dotenv;
+mod toml;
+mod yaml;
+
+/// Keys in this namespace are used for knob's own bookkeeping.
+static INTERNAL_PREFIX: &'static str = "knob.";
+
+/// The key the program name is stored under by `load_args`.
+static PROGNAME: &'static str = "knob.progname";
+
+/// The key the free command line arguments are stored under by `load_args`.
+static FREE_ARGS: &'static str = "knob.free";
+
+fn is_internal(key: &str) -> bool {
+  key.starts_with(INTERNAL_PREFIX)
+}
+
+/// Replace `${VAR}` in a value with the environment variable `VAR` and `$$`
+/// with `$`.
+fn expand_env_vars(value: &str, keep_unknown: bool) -> String {
+  let mut result = String::new();
+  let mut rest = value;
+  loop {
+    let start = match rest.find('$') {
+      Some(start) => { start },
+      None => {
+        result.push_str(rest);
+        return result
+      }
+    };
+    result.push_str(rest.slice_to(start));
+    rest = rest.slice_from(start);
+    if rest.starts_with("$$") {
+      result.push_char('$');
+      rest = rest.slice_from(2);
+    } else if rest.starts_with("${") && rest.contains_char('}') {
+      let end = rest.find('}').unwrap();
+      match os::getenv(rest.slice(2, end)) {
+        Some(var) => { result.push_str(var.as_slice()) },
+        None => {
+          if keep_unknown {
+            result.push_str(rest.slice_to(end + 1))
+          }
+        }
+      }
+      rest = rest.slice_from(end + 1);
+    } else {
+      result.push_char('$');
+      rest = rest.slice_from(1);
+    }
+  }
+}
+
+/// The Levenshtein distance between two strings.
+fn edit_distance(a: &str, b: &str) -> uint {
+  let b: Vec<char> = b.chars().collect();
+  let mut previous: Vec<uint> = range(0, b.len() + 1).collect();
+  for (i, a_char) in a.chars().enumerate() {
+    let mut current = vec!(i + 1);
+    for (j, b_char) in b.iter().enumerate() {
+      let substitution = *previous.get(j) + if a_char == *b_char { 0 } else { 1 };
+      let insertion = *current.get(j) + 1;
+      let deletion = *previous.get(j + 1) + 1;
+      current.push(cmp::min(substitution, cmp::min(insertion, deletion)));
+    }
+    previous = current;
+  }
+  *previous.get(b.len())
+}
+
+/// The position of the last occurrence of an option in a list of command line
+/// arguments, including the program name. Values of the registered `options`
+/// are skipped, whether they are attached like `-ofile` and `--output=file` or
+/// given as the next argument.
+fn last_position(args: &[String], options: &[OptGroup], short_name: &str, long_name: &str) -> Option<uint> {
+  let mut position = None;
+  let mut skip_value = false;
+  for (index, arg) in args.iter().enumerate().skip(1) {
+    let arg = arg.as_slice();
+    if skip_value {
+      skip_value = false;
+      continue
+    }
+    if arg == "--" {
+      break
+    }
+    if arg.starts_with("--") {
+      let name = arg.slice_from(2).split('=').next().unwrap();
+      if !long_name.is_empty() && name == long_name {
+        position = Some(index);
+      }
+      let opt = options.iter().find(|opt| opt.long_name.as_slice() == name);
+      skip_value = !arg.contains_char('=') && value_follows(opt, args, index);
+    } else if arg.starts_with("-") {
+      for (offset, c) in arg.char_indices().skip(1) {
+        let end = offset + c.len_utf8_bytes();
+        let name = arg.slice(offset, end);
+        if name == short_name {
+          position = Some(index);
+        }
+        match options.iter().find(|opt| opt.short_name.as_slice() == name) {
+          Some(opt) if opt.hasarg != No => {
+            skip_value = end == arg.len() && value_follows(Some(opt), args, index);
+            break
+          },
+          _ => {}
+        }
+      }
+    }
+  }
+  position
+}
+
+/// Whether the argument after `index` is the value of the option given there,
+/// like getopts reads it.
+fn value_follows(opt: Option<&OptGroup>, args: &[String], index: uint) -> bool {
+  match opt.map(|opt| &opt.hasarg) {
+    Some(&Yes) => { true },
+    Some(&Maybe) => {
+      index + 1 < args.len() &&
+        !(args[index + 1].len() > 1 && args[index + 1].as_slice().starts_with("-"))
+    },
+    _ => { false }
+  }
+}
+
+/// The column option descriptions start at in usage strings, as in getopts.
+static DESCRIPTION_COLUMN: uint = 24;
+
+/// Split a text into lines of at most `width` characters at word boundaries.
+/// Words longer than `width` are put on a line of their own.
+fn wrap_words(text: &str, width: uint) -> Vec<String> {
+  let mut lines = Vec::new();
+  let mut line = String::new();
+  for word in text.words() {
+    if !line.is_empty() && line.len() + 1 + word.len() > width {
+      lines.push(mem::replace(&mut line, String::new()));
+    }
+    if !line.is_empty() {
+      line.push_char(' ');
+    }
+    line.push_str(word);
+  }
+  if !line.is_empty() {
+    lines.push(line);
+  }
+  lines
+}
+
+/// The separator between the elements of list settings.
+static LIST_SEPARATOR: &'static str = "\x1f";
+
+/// A type that can be used as a key for settings. It is implemented for all
+/// types implementing `Show`, using their string representation. Implement it
+/// directly for key types without `Show` to control how they are stored.
+pub trait Key {
+  /// The string the setting for this key is stored under.
+  fn as_key(&self) -> String;
+}
+
+impl<T: ToStr> Key for T {
+  fn as_key(&self) -> String {
+    self.to_str()
+  }
+}
+
+/// A check whether a raw value has the expected type, as used by
+/// `Settings::validate_types`.
+pub type TypeCheck = fn(&str) -> bool;
+
+/// A `TypeCheck` that checks whether a value can be parsed into `T`.
+pub fn parses<T: FromStr>(value: &str) -> bool {
+  from_str::<T>(value).is_some()
+}
+
+/// Parse a boolean from the common spellings `true`/`false`, `yes`/`no`,
+/// `on`/`off` and `1`/`0`, ignoring case. Returns `None` for anything else,
+/// including empty strings.
+pub fn parse_bool(value: &str) -> Option<bool> {
+  match value.to_ascii_lower().as_slice() {
+    "true" | "yes" | "on" | "1" => { Some(true) },
+    "false" | "no" | "off" | "0" => { Some(false) },
+    _ => { None }
+  }
+}
+
+/// Parse a duration given as a whole number with one of the units `ms`, `s`,
+/// `m`, `h` or `d`, like `30s`. Bare numbers are rejected, and so are amounts
+/// that don't fit into a `Duration`.
+fn parse_duration(value: &str) -> Option<Duration> {
+  let split = value.find(|c: char| !c.is_digit()).unwrap_or(value.len());
+  let amount: i64 = match from_str(value.slice_to(split)) {
+    Some(amount) => { amount },
+    None => { return None }
+  };
+  let millis_per_unit: i64 = match value.slice_from(split) {
+    "ms" => { 1 },
+    "s" => { 1000 },
+    "m" => { 60 * 1000 },
+    "h" => { 60 * 60 * 1000 },
+    "d" => { 24 * 60 * 60 * 1000 },
+    _ => { return None }
+  };
+  amount.checked_mul(&millis_per_unit).map(|millis| Duration::milliseconds(millis))
+}
+
+/// Parse an integer that may contain underscores between its digits and may
+/// be prefixed with `0x`, `0o` or `0b` for hexadecimal, octal or binary.
+fn parse_int_radix(value: &str) -> Option<i64> {
+  let (negative, value) = if value.starts_with("-") {
+    (true, value.slice_from(1))
+  } else {
+    (false, value)
+  };
+  let prefix = value.to_ascii_lower();
+  let (radix, digits) = if prefix.as_slice().starts_with("0x") {
+    (16, value.slice_from(2))
+  } else if prefix.as_slice().starts_with("0o") {
+    (8, value.slice_from(2))
+  } else if prefix.as_slice().starts_with("0b") {
+    (2, value.slice_from(2))
+  } else {
+    (10, value)
+  };
+  let digits: String = digits.chars().filter(|&c| c != '_').collect();
+  if digits.is_empty() || digits.as_slice().starts_with("-") || digits.as_slice().starts_with("+") {
+    return None
+  }
+  from_str_radix::<i64>(digits.as_slice(), radix).map(|number| {
+    if negative { -number } else { number }
+  })
+}
+
+/// The error returned when a stored setting could not be parsed into the
+/// requested type.
+#[deriving(Show, PartialEq, Clone)]
+pub struct ParseError {
+  /// The key the setting was stored under.
+  pub key: String,
+  /// The raw string that failed to parse.
+  pub value: String,
+}
+
+/// The errors that can occur while loading settings. Errors from parsing
+/// command line arguments carry the name of the offending option.
+#[deriving(PartialEq, Clone)]
+pub enum Error {
+  /// An option that requires an argument was given without one.
+  ArgumentMissing(String),
+  /// An option was given that was not registered.
+  UnrecognizedOption(String),
+  /// A required option was not given.
+  OptionMissing(String),
+  /// An option that may only occur once was given more than once.
+  OptionDuplicated(String),
+  /// An argument was given to an option that does not take one.
+  UnexpectedArgument(String),
+  /// The input could not be parsed as JSON.
+  InvalidJson(String),
+  /// The input could not be parsed as TOML.
+  InvalidToml(String),
+  /// The input could not be parsed as YAML.
+  InvalidYaml(String),
+  /// The value for the given key is not a scalar.
+  UnsupportedValue(String),
+  /// The value for the given key was rejected by its validation rule, with the
+  /// message returned by the rule.
+  InvalidValue(String, String),
+  /// A value references a setting that is not present, as reported by
+  /// `Settings::interpolate`.
+  MissingReference(String),
+  /// The value for the given key references itself, directly or through
+  /// other settings.
+  CyclicReference(String),
+  /// The key is not allowed in strict mode, see `Settings::set_strict`.
+  UnknownKey(String),
+  /// No setting is present for the given key, as reported by `Settings::get`.
+  Missing(String),
+  /// The setting for the given key could not be parsed, with the raw value.
+  Parse(String, String),
+}
+
+impl Error {
+  fn from_fail(fail: Fail_) -> Error {
+    match fail {
+      getopts::ArgumentMissing(name) => { ArgumentMissing(name) },
+      getopts::UnrecognizedOption(name) => { UnrecognizedOption(name) },
+      getopts::OptionMissing(name) => { OptionMissing(name) },
+      getopts::OptionDuplicated(name) => { OptionDuplicated(name) },
+      getopts::UnexpectedArgument(name) => { UnexpectedArgument(name) },
+    }
+  }
+}
+
+impl fmt::Show for Error {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      ArgumentMissing(ref name) => { write!(f, "argument to option '{}' missing", name) },
+      UnrecognizedOption(ref name) => { write!(f, "unrecognized option: '{}'", name) },
+      OptionMissing(ref name) => { write!(f, "required option '{}' missing", name) },
+      OptionDuplicated(ref name) => { write!(f, "option '{}' given more than once", name) },
+      UnexpectedArgument(ref name) => { write!(f, "option '{}' does not take an argument", name) },
+      InvalidJson(ref message) => { write!(f, "invalid JSON: {}", message) },
+      InvalidToml(ref message) => { write!(f, "invalid TOML: {}", message) },
+      InvalidYaml(ref message) => { write!(f, "invalid YAML: {}", message) },
+      UnsupportedValue(ref key) => { write!(f, "value for '{}' is not a string, number or boolean", key) },
+      InvalidValue(ref key, ref message) => { write!(f, "invalid value for '{}': {}", key, message) },
+      MissingReference(ref key) => { write!(f, "referenced setting '{}' missing", key) },
+      CyclicReference(ref key) => { write!(f, "value for '{}' references itself", key) },
+      UnknownKey(ref key) => { write!(f, "unknown key: '{}'", key) },
+      Missing(ref key) => { write!(f, "setting '{}' missing", key) },
+      Parse(ref key, ref value) => { write!(f, "value for '{}' could not be parsed: '{}'", key, value) },
+    }
+  }
+}
+
+/// The source layers a setting can be loaded from, in ascending order of
+/// precedence.
+#[deriving(Show, PartialEq, Eq, PartialOrd, Ord, Clone)]
+pub enum Layer {
+  /// Defaults provided by the program.
+  DefaultLayer,
+  /// Settings loaded through `load_file`, `load_json`, `load_toml` and `load_yaml`.
+  FileLayer,
+  /// Settings loaded through `load_env`.
+  EnvLayer,
+  /// Settings loaded through `load_args`.
+  CliLayer,
+}
+
+/// The sources a setting can come from.
+#[deriving(Show, PartialEq, Clone)]
+pub enum Source {
+  /// Settings set by the program, through `set` or the `DefaultLayer`.
+  ManualSource,
+  /// Settings loaded through `load_file` or the `FileLayer`.
+  FileSource,
+  /// Settings loaded through `load_env` or the `EnvLayer`.
+  EnvSource,
+  /// Settings loaded through `load_args` or the `CliLayer`.
+  CommandLineSource,
+}
+
+impl Layer {
+  fn source(&self) -> Source {
+    match *self {
+      DefaultLayer => { ManualSource },
+      FileLayer => { FileSource },
+      EnvLayer => { EnvSource },
+      CliLayer => { CommandLineSource },
+    }
+  }
+}
+
+/// A difference between two Settings structs, as returned by `Settings::diff`.
+#[deriving(Show, PartialEq, Clone)]
+pub enum Change {
+  /// The key is only present in the other settings.
+  Added(String),
+  /// The key is only present in these settings.
+  Removed(String),
+  /// The key has a different value in the other settings, with the old and
+  /// the new value.
+  Modified(String, String, String),
+}
+
+/// The description of a registered command line option, as returned by
+/// `Settings::describe_options`. Absent names and hints are empty.
+#[deriving(Show, PartialEq, Clone)]
+pub struct OptionInfo {
+  /// The short name of the option, without the leading dash.
+  pub short: String,
+  /// The long name of the option, without the leading dashes.
+  pub long: String,
+  /// The description shown in the usage string.
+  pub description: String,
+  /// The hint for the option's argument.
+  pub hint: String,
+  /// Whether the option has to be given.
+  pub required: bool,
+}
+
+/// The kinds of command line options an `OptSpec` can describe.
+#[deriving(Show, PartialEq, Clone)]
+pub enum OptKind {
+  /// A flag without a value, like `optflag`.
+  FlagKind,
+  /// An option with a value, like `optopt`.
+  ValueKind,
+  /// A flag with an optional value, like `optflagopt`.
+  FlagValueKind,
+  /// An option that can be given more than once, like `optmulti`.
+  MultiKind,
+}
+
+/// The declaration of a command line option, as registered by
+/// `Settings::add_options`.
+#[deriving(Show, PartialEq, Clone)]
+pub struct OptSpec<'a> {
+  /// The short name of the option, without the leading dash, or an empty
+  /// string.
+  pub short: &'a str,
+  /// The long name of the option, without the leading dashes.
+  pub long: &'a str,
+  /// The description shown in the usage string.
+  pub desc: &'a str,
+  /// The hint for the option's argument.
+  pub hint: &'a str,
+  /// Whether the option has to be given.
+  pub required: bool,
+  /// The kind of the option.
+  pub kind: OptKind,
+}
+
+/// The values parsed by `Settings::fetch_cached`, keyed by the setting and
+/// the type they were parsed into.
+///
+/// The cache is kept apart from the settings, so fetching from settings shared
+/// between tasks needs no locking. The kept values are dropped whenever a
+/// setting changes, and whenever the cache is used with another Settings
+/// struct than before, including clones.
+pub struct FetchCache {
+  version: uint,
+  values: HashMap<(String, TypeId), Box<Any>>,
+}
+
+impl FetchCache {
+  /// Create an empty cache.
+  pub fn new() -> FetchCache {
+    FetchCache { version: 0, values: HashMap::new() }
+  }
+}
+
+/// The last version given to stored settings, see `Version`.
+static NEXT_VERSION: AtomicUint = INIT_ATOMIC_UINT;
+
+/// The version of the stored settings, unique among all Settings structs. It
+/// changes whenever a setting changes, and a clone gets its own version.
+struct Version {
+  number: uint,
+}
+
+impl Version {
+  fn new() -> Version {
+    Version { number: NEXT_VERSION.fetch_add(1, SeqCst) + 1 }
+  }
+}
+
+impl Clone for Version {
+  fn clone(&self) -> Version {
+    Version::new()
+  }
+}
+
+/// The number of settings that failed to parse, as returned by
+/// `Settings::parse_error_count`. Atomic, so fetching stays possible from
+/// several tasks sharing the settings. A clone starts with the same count.
+struct ParseErrorCount {
+  count: AtomicUint,
+}
+
+impl ParseErrorCount {
+  fn new(count: uint) -> ParseErrorCount {
+    ParseErrorCount { count: AtomicUint::new(count) }
+  }
+
+  fn increment(&self) {
+    self.count.fetch_add(1, SeqCst);
+  }
+
+  fn get(&self) -> uint {
+    self.count.load(SeqCst)
+  }
+}
+
+impl Clone for ParseErrorCount {
+  fn clone(&self) -> ParseErrorCount {
+    ParseErrorCount::new(self.get())
+  }
+}
+
+/// The channels registered through `Settings::on_change`. They are kept behind
+/// a lock, as senders can't be shared between tasks otherwise. A clone has no
+/// channels, so changes to a copy of the settings are not reported.
+struct Observers {
+  senders: Mutex<Vec<Sender<(String, String)>>>,
+}
+
+impl Observers {
+  fn new() -> Observers {
+    Observers { senders: Mutex::new(Vec::new()) }
+  }
+
+  fn push(&self, sender: Sender<(String, String)>) {
+    let mut senders = self.senders.lock();
+    senders.push(sender);
+  }
+
+  fn notify(&self, key: &String, value: &String) {
+    let senders = self.senders.lock();
+    for sender in senders.iter() {
+      sender.send_opt((key.clone(), value.clone())).ok();
+    }
+  }
+}
+
+impl Clone for Observers {
+  fn clone(&self) -> Observers {
+    Observers::new()
+  }
+}
 
 /// The settings structure we save the options and settings in.
+#[deriving(Clone)]
 pub struct Settings {
   store: HashMap<String,String>,
+  order: Vec<String>,
+  sources: HashMap<String,Source>,
+  layers: HashMap<String,Layer>,
   options: Box<Vec<OptGroup>>,
+  aliases: HashMap<String,String>,
+  negatable: Vec<String>,
+  opt_defaults: HashMap<String,String>,
+  provided: Vec<String>,
+  validators: HashMap<String,fn(&str) -> Result<(), String>>,
+  case_insensitive: bool,
+  trim_values: bool,
+  version: Version,
+  parse_errors: ParseErrorCount,
+  env_fallback: Option<String>,
+  locked: Vec<String>,
+  allowed: Vec<String>,
+  strict: bool,
+  observers: Observers,
 }
 
 impl Settings {
   /// Create a new Settings struct.
   pub fn new() -> Settings {
-    Settings { store: HashMap::new(), options: box Vec::new() }
+    Settings {
+      store: HashMap::new(),
+      order: Vec::new(),
+      sources: HashMap::new(),
+      layers: HashMap::new(),
+      options: box Vec::new(),
+      aliases: HashMap::new(),
+      negatable: Vec::new(),
+      opt_defaults: HashMap::new(),
+      provided: Vec::new(),
+      validators: HashMap::new(),
+      case_insensitive: false,
+      trim_values: false,
+      version: Version::new(),
+      parse_errors: ParseErrorCount::new(0),
+      env_fallback: None,
+      locked: Vec::new(),
+      allowed: Vec::new(),
+      strict: false,
+      observers: Observers::new(),
+    }
   }
 
-  /// Set a settings key to a value. The value will be serialized.
-  pub fn set<A: ToStr, T: ToStr>(&mut self, setting: A, value: T) {
-    self.store.swap(setting.to_str(), value.to_str());
+  /// Set a settings key to a value, returning the Settings struct for chaining.
+  ///
+  /// ~~~
+  /// extern crate getopts;
+  /// extern crate knob;
+  ///
+  /// use getopts::optopt;
+  /// use knob::Settings;
+  ///
+  /// fn main() {
+  ///   let settings = Settings::new()
+  ///     .with("port", 8080)
+  ///     .with_opt(optopt("p", "port", "the port to bind to", "4000"))
+  ///     .build();
+  ///   assert_eq!(settings.fetch("port"), Some(8080));
+  /// }
+  /// ~~~
+  pub fn with<A: Key, T: ToStr>(mut self, setting: A, value: T) -> Settings {
+    self.set(setting, value);
+    self
   }
 
-  /// Set a value using an Option struct. The value will only be set if the
-  /// value is not None. This way, you can avoid unwrapping the result of a
-  /// previous operation by yourself.
-  pub fn set_opt<A: ToStr, T: ToStr>(&mut self, setting: A, value: Option<T>) {
-    if value.is_some() {
-      self.store.swap(setting.to_str(), value.unwrap().to_str());
+  /// Register a commandline for later use with load_args, returning the
+  /// Settings struct for chaining.
+  pub fn with_opt(mut self, opt: OptGroup) -> Settings {
+    self.opt(opt);
+    self
+  }
+
+  /// Finish a chain of `with` and `with_opt` calls.
+  pub fn build(self) -> Settings {
+    self
+  }
+
+  /// Read settings that are not present from the environment when fetching
+  /// them with `fetch`, `try_fetch` or any other `fetch_` method. The variable is named like the key in
+  /// uppercase, prefixed with `prefix` and an underscore, so `port` is read
+  /// from `MYAPP_PORT` for the prefix `MYAPP`. An empty prefix reads the
+  /// uppercased key only.
+  ///
+  /// Stored settings always win over the environment. Nothing is stored.
+  pub fn enable_env_fallback(&mut self, prefix: &str) {
+    self.env_fallback = Some(prefix.to_string());
+  }
+
+  /// Add keys to the keys allowed in files and JSON input in strict mode.
+  pub fn allow_keys(&mut self, settings: &[&str]) {
+    for setting in settings.iter() {
+      let key = self.key(*setting);
+      if !self.allowed.contains(&key) {
+        self.allowed.push(key);
+      }
     }
   }
 
-  /// Fetch a setting for a key. Fails if the setting is present but could not be
-  /// parsed.
-  pub fn fetch<A: ToStr, T: FromStr>(&self, setting: A) -> Option<T> {
-    match self.store.find(&setting.to_str()) {
-      Some(string) => {
-        let value = from_str(string.as_slice());
-        if value.is_none() {
-          fail!("setting could not be parsed: {:?}", setting.to_str())
+  /// Enable or disable strict mode. In strict mode, `load_file`,
+  /// `load_dotenv`, `load_json`, `load_toml` and `load_yaml` reject keys that
+  /// were not allowed through `allow_keys`, to catch misspelled keys.
+  pub fn set_strict(&mut self, strict: bool) {
+    self.strict = strict;
+  }
+
+  fn is_allowed(&self, key: &str) -> bool {
+    !self.strict || self.allowed.contains(&self.key(key))
+  }
+
+  /// Remove whitespace around all values that are set or loaded from now on.
+  /// Note that a value consisting only of whitespace is stored as an empty
+  /// string then.
+  pub fn set_trim_values(&mut self, trim_values: bool) {
+    self.trim_values = trim_values;
+  }
+
+  /// Make keys case insensitive by lowercasing them whenever a setting is
+  /// stored or looked up. Enabling this lowercases the keys of all settings that
+  /// are already present; if two keys only differ in case, one of the values
+  /// is lost. Locked and allowed keys and the keys of registered options are
+  /// lowercased as well.
+  pub fn set_case_insensitive(&mut self, case_insensitive: bool) {
+    self.case_insensitive = case_insensitive;
+    if case_insensitive {
+      let observers = mem::replace(&mut self.observers, Observers::new());
+      let order = mem::replace(&mut self.order, Vec::new());
+      let mut store = mem::replace(&mut self.store, HashMap::new());
+      let mut sources = mem::replace(&mut self.sources, HashMap::new());
+      for key in order.move_iter() {
+        let value = store.pop(&key).unwrap();
+        let source = sources.pop(&key).unwrap();
+        self.insert(key.as_slice().to_ascii_lower(), value, source);
+      }
+      let layers = mem::replace(&mut self.layers, HashMap::new());
+      for (key, layer) in layers.move_iter() {
+        self.layers.swap(key.as_slice().to_ascii_lower(), layer);
+      }
+      let locked = mem::replace(&mut self.locked, Vec::new());
+      for key in locked.iter() {
+        self.lock(key.as_slice());
+      }
+      let allowed = mem::replace(&mut self.allowed, Vec::new());
+      for key in allowed.iter() {
+        self.allow_keys([key.as_slice()]);
+      }
+      let aliases = mem::replace(&mut self.aliases, HashMap::new());
+      for (long_name, canonical) in aliases.move_iter() {
+        let long_name = self.key(long_name);
+        let canonical = self.key(canonical);
+        self.aliases.swap(long_name, canonical);
+      }
+      let opt_defaults = mem::replace(&mut self.opt_defaults, HashMap::new());
+      for (long_name, default) in opt_defaults.move_iter() {
+        let long_name = self.key(long_name);
+        self.opt_defaults.swap(long_name, default);
+      }
+      let negatable = mem::replace(&mut self.negatable, Vec::new());
+      for long_name in negatable.move_iter() {
+        let long_name = self.key(long_name);
+        if !self.negatable.contains(&long_name) {
+          self.negatable.push(long_name);
         }
-        value
-      },
-      None => { None }
+      }
+      self.observers = observers;
     }
   }
 
-  /// Fetch a setting for a key and pass it to the given function. The result of the function
-  /// will be returned.
-  pub fn fetch_with<A: ToStr, T: FromStr>(&self, setting: A, f: |Option<T>| -> T) -> T {
-    let value = self.fetch(setting.to_str());
-    f(value)
+  fn key<A: Key>(&self, setting: A) -> String {
+    let key = setting.as_key();
+    if self.case_insensitive { key.as_slice().to_ascii_lower() } else { key }
   }
 
-  /// Register a commandline for later use with load_args.
+  fn value<T: ToStr>(&self, value: T) -> String {
+    let value = value.to_str();
+    if self.trim_values { value.as_slice().trim().to_string() } else { value }
+  }
+
+  fn insert(&mut self, key: String, value: String, source: Source) {
+    self.version = Version::new();
+    if !self.store.contains_key(&key) {
+      self.order.push(key.clone());
+    }
+    if self.store.find(&key) != Some(&value) {
+      self.observers.notify(&key, &value);
+    }
+    self.sources.swap(key.clone(), source);
+    self.store.swap(key, value);
+  }
+
+  /// Change the value of a present setting, keeping its source and layer.
+  fn replace(&mut self, key: String, value: String) {
+    let source = self.sources.get(&key).clone();
+    self.insert(key, value, source);
+  }
+
+  /// Register a channel to send the key and the new value to whenever a
+  /// setting is set, loaded or changed by `expand_env` or `interpolate`.
+  /// Setting a key to the value it already holds does not send anything.
+  /// Removing settings does not send anything either.
   ///
-  /// Currently, only optopt and reqopt are properly supported.
-  pub fn opt(&mut self, opt: OptGroup) {
-    self.options.push(opt);
+  /// Clones of the settings don't send anything to the registered channels.
+  pub fn on_change(&mut self, observer: Sender<(String, String)>) {
+    self.observers.push(observer);
   }
 
-  /// Load the command line argument given by the OS.
+  fn remove(&mut self, key: &String) -> Option<String> {
+    self.version = Version::new();
+    self.order.retain(|ordered| ordered != key);
+    self.sources.pop(key);
+    self.store.pop(key)
+  }
+
+  /// Create a view on all settings in the namespace `prefix`. For example,
+  /// `settings.scope("db").fetch("port")` fetches the setting `db.port`.
+  pub fn scope<'a>(&'a mut self, prefix: &str) -> ScopedSettings<'a> {
+    ScopedSettings { settings: self, prefix: prefix.to_string() }
+  }
+
+  /// Get the entry for a key, for conditional changes in place. For example,
+  /// `settings.entry("port").or_set(8080)` sets the port unless it is present.
+  pub fn entry<'a, A: Key>(&'a mut self, setting: A) -> Entry<'a> {
+    let key = self.key(setting);
+    Entry { settings: self, key: key }
+  }
+
+  /// Set a settings key to a value. The value will be serialized.
   ///
-  /// Optionally returns failures.
-  pub fn load_os_args(&mut self) -> Option<Fail_> {
-    self.load_args(os::args())
+  /// The value is always stored and is not associated with any layer, so it can
+  /// be overridden by any call to `set_layered`.
+  ///
+  /// Fails if the value is rejected by the validation rule for the key.
+  pub fn set<A: Key, T: ToStr>(&mut self, setting: A, value: T) {
+    match self.try_set(setting, value) {
+      Ok(()) => {},
+      Err(error) => { fail!("setting could not be set: {}", error) }
+    }
+  }
+
+  /// Set a settings key to a value like `set`, but return an error instead of
+  /// failing if the value is rejected by the validation rule for the key.
+  pub fn try_set<A: Key, T: ToStr>(&mut self, setting: A, value: T) -> Result<(), Error> {
+    let key = self.key(setting);
+    if self.locked.contains(&key) {
+      return Ok(())
+    }
+    let value = self.value(value);
+    try!(self.check(&key, value.as_slice()));
+    self.layers.pop(&key);
+    self.insert(key, value, ManualSource);
+    Ok(())
+  }
+
+  /// Set a settings key to a value like `try_set`, but check that the stored
+  /// string parses back into the same value first. This catches types whose
+  /// `Show` and `FromStr` implementations don't match. Nothing is stored if
+  /// the check fails.
+  pub fn set_checked<A: Key, T: ToStr + FromStr + PartialEq>(&mut self, setting: A, value: T) -> Result<(), String> {
+    let key = self.key(setting);
+    let string = value.to_str();
+    match from_str::<T>(string.as_slice()) {
+      Some(ref parsed) if *parsed == value => {},
+      _ => { return Err(format!("value for '{}' does not parse back: '{}'", key, string)) }
+    }
+    self.try_set(key, string).map_err(|error| error.to_str())
   }
 
-  /// Load a list of command line arguments.
+  /// Register a rule to validate all values for a key with. The rule returns
+  /// an error message for invalid values. Values are validated whenever they
+  /// are set or loaded for the key.
   ///
-  /// Automatically sets "knob.progname" to the name of the program.
+  /// If a value is already present for the key, it is validated immediately.
+  /// It is kept even if it is invalid.
+  pub fn validate<A: Key>(&mut self, setting: A, rule: fn(&str) -> Result<(), String>) -> Result<(), Error> {
+    let key = self.key(setting);
+    self.validators.swap(key.clone(), rule);
+    match self.store.find(&key) {
+      Some(value) => { self.check(&key, value.as_slice()) },
+      None => { Ok(()) }
+    }
+  }
+
+  fn check(&self, key: &String, value: &str) -> Result<(), Error> {
+    match self.validators.find(key) {
+      Some(rule) => { (*rule)(value).map_err(|message| InvalidValue(key.clone(), message)) },
+      None => { Ok(()) }
+    }
+  }
+
+  /// Set a settings key to a value only if no value is present yet, so values
+  /// set earlier are never overwritten. An empty value counts as present.
+  pub fn set_default<A: Key, T: ToStr>(&mut self, setting: A, value: T) {
+    let key = self.key(setting);
+    if !self.store.contains_key(&key) {
+      self.set(key, value);
+    }
+  }
+
+  /// Set a settings key to a value loaded from the given layer.
   ///
-  /// Optionally returns failures.
-  pub fn load_args(&mut self, args: Vec<String>) -> Option<Fail_> {
-    let ref prog_name = args.get(0);
+  /// The value is only stored if no value from a layer with a higher precedence
+  /// is present. Fails if the value is rejected by the validation rule for the
+  /// key.
+  pub fn set_layered<A: Key, T: ToStr>(&mut self, setting: A, value: T, layer: Layer) {
+    match self.try_set_layered(setting, value, layer) {
+      Ok(()) => {},
+      Err(error) => { fail!("setting could not be set: {}", error) }
+    }
+  }
 
-    self.set("knob.progname", prog_name.clone());
+  fn try_set_layered<A: Key, T: ToStr>(&mut self, setting: A, value: T, layer: Layer) -> Result<(), Error> {
+    let key = self.key(setting);
+    let overrides = match self.layers.find(&key) {
+      Some(current) => { layer >= *current },
+      None => { true }
+    } && !self.locked.contains(&key);
+    if overrides {
+      let value = self.value(value);
+      try!(self.check(&key, value.as_slice()));
+      self.insert(key.clone(), value, layer.source());
+      self.layers.swap(key, layer);
+    }
+    Ok(())
+  }
 
-    let matches = match getopts(args.tail(), self.options.as_slice()) {
-      Ok(m) => { m }
-      Err(fail) => { return Some(fail) }
-    };
+  /// Lock the setting for a key, so values set or loaded for it are ignored
+  /// until it is unlocked. A locked setting is not changed or removed by
+  /// `unset`, `rename`, `merge`, `clear`, `restore`, `expand_env` or
+  /// `interpolate` either. The key doesn't need to be present.
+  pub fn lock<A: Key>(&mut self, setting: A) {
+    let key = self.key(setting);
+    if !self.locked.contains(&key) {
+      self.locked.push(key);
+    }
+  }
 
-    let given_options = self.options.clone();
-    for opt in given_options.iter() {
-      let opt_strings = &[opt.short_name.clone(), opt.long_name.clone()];
-      self.set_opt(opt.long_name.clone(), matches.opts_str(opt_strings))
+  /// Unlock the setting for a key locked by `lock`.
+  pub fn unlock<A: Key>(&mut self, setting: A) {
+    let key = self.key(setting);
+    self.locked.retain(|locked| *locked != key);
+  }
+
+  /// Check whether the setting for a key is locked.
+  pub fn is_locked<A: Key>(&self, setting: A) -> bool {
+    self.locked.contains(&self.key(setting))
+  }
+
+  /// Set a value using an Option struct. The value will only be set if the
+  /// value is not None. This way, you can avoid unwrapping the result of a
+  /// previous operation by yourself.
+  pub fn set_opt<A: Key, T: ToStr>(&mut self, setting: A, value: Option<T>) {
+    if value.is_some() {
+      self.set(setting, value.unwrap());
+    }
+  }
+
+  /// Set a value only if `condition` holds, without wrapping the call to `set`
+  /// in an `if` block.
+  pub fn set_if<A: Key, T: ToStr>(&mut self, setting: A, value: T, condition: bool) {
+    if condition {
+      self.set(setting, value);
+    }
+  }
+
+  /// Set a settings key to a list of values. The values will be serialized.
+  ///
+  /// Fails if one of the serialized values contains the list separator `\x1f`.
+  /// Note that an empty list and a list holding a single empty string are stored
+  /// the same way and both fetched as an empty list.
+  pub fn set_list<A: Key, T: ToStr>(&mut self, setting: A, values: &[T]) {
+    let values: Vec<String> = values.iter().map(|value| value.to_str()).collect();
+    for value in values.iter() {
+      if value.as_slice().contains(LIST_SEPARATOR) {
+        fail!("list value contains the list separator: {:?}", value)
+      }
+    }
+    self.set(setting, values.connect(LIST_SEPARATOR));
+  }
+
+  /// Append a value to the list stored for a key, as read by `fetch_list`. A
+  /// key that is not present is set to a list holding only the value, and a
+  /// single value stored for the key is treated as a list holding it.
+  ///
+  /// Fails if the serialized value contains the list separator `\x1f`.
+  pub fn push<A: Key, T: ToStr>(&mut self, setting: A, value: T) {
+    let key = self.key(setting);
+    let value = value.to_str();
+    if value.as_slice().contains(LIST_SEPARATOR) {
+      fail!("list value contains the list separator: {:?}", value)
+    }
+    let list = match self.store.find(&key) {
+      Some(list) if !list.is_empty() => { format!("{}{}{}", list, LIST_SEPARATOR, value) },
+      _ => { value }
     };
-    None
+    self.set(key, list);
   }
 
-  /// Returns the usage string for the stored OptGroups. Pass `brief`
-  /// to have a brief message included before the usage strings.
-  pub fn usage(&self, brief: String) -> String {
-    usage(brief.as_slice(), self.options.as_slice())
+  /// Set all keys to values from an iterator of pairs. Later pairs win on
+  /// duplicate keys.
+  pub fn extend<A: Key, T: ToStr, I: Iterator<(A, T)>>(&mut self, mut iter: I) {
+    for (setting, value) in iter {
+      self.set(setting, value);
+    }
   }
-}
 
+  /// Merge all settings and registered options of another Settings struct
+  /// into this one. Settings of `other` win on conflicts unless they are
+  /// locked here, options already registered under the same long name are
+  /// skipped.
+  pub fn merge(&mut self, other: &Settings) {
+    for (other_key, value) in other.iter() {
+      let key = self.key(other_key.as_slice());
+      if self.locked.contains(&key) {
+        continue
+      }
+      self.insert(key.clone(), value.clone(), other.sources.get(other_key).clone());
+      match other.layers.find(other_key) {
+        Some(layer) => { self.layers.swap(key, layer.clone()); },
+        None => { self.layers.pop(&key); }
+      }
+    }
+    for opt in other.options.iter() {
+      if !self.options.iter().any(|own| own.long_name == opt.long_name) {
+        self.options.push(opt.clone());
+        match other.aliases.find(&other.key(opt.long_name.as_slice())) {
+          Some(canonical) => {
+            let long_name = self.key(opt.long_name.as_slice());
+            let canonical = self.key(canonical.as_slice());
+            self.aliases.swap(long_name, canonical);
+          },
+          None => {}
+        }
+      }
+    }
+  }
+
+  /// Compare these settings to `other`. Returns the changes needed to get from
+  /// these settings to `other`: removed and modified keys in the order they
+  /// were set here, followed by the added keys in the order they were set in
+  /// `other`.
+  pub fn diff(&self, other: &Settings) -> Vec<Change> {
+    let mut changes = Vec::new();
+    for (key, value) in self.iter() {
+      match other.store.find(key) {
+        None => { changes.push(Removed(key.clone())) },
+        Some(new) if new != value => {
+          changes.push(Modified(key.clone(), value.clone(), new.clone()))
+        },
+        _ => {}
+      }
+    }
+    for key in other.keys() {
+      if !self.store.contains_key(key) {
+        changes.push(Added(key.clone()));
+      }
+    }
+    changes
+  }
+
+  /// Replace references to environment variables like `${HOME}` in all stored
+  /// values with the value of the variable. `$$` is replaced by a single `$`.
+  ///
+  /// References to unknown variables are left as they are if `keep_unknown` is
+  /// set and removed otherwise.
+  pub fn expand_env(&mut self, keep_unknown: bool) {
+    let expanded: Vec<(String, String)> = self.order.iter().map(|key| {
+      (key.clone(), expand_env_vars(self.store.get(key).as_slice(), keep_unknown))
+    }).collect();
+    for (key, value) in expanded.move_iter() {
+      if !self.locked.contains(&key) {
+        self.replace(key, value);
+      }
+    }
+  }
+
+  /// Replace references to other settings like `${host}` in all stored values
+  /// with the value of that setting, which is interpolated first. Other uses
+  /// of `$` are left as they are.
+  ///
+  /// Nothing is changed if a referenced setting is missing or a value
+  /// references itself. This includes the references in locked settings:
+  /// they are resolved like all others, but their stored values are kept.
+  pub fn interpolate(&mut self) -> Result<(), Error> {
+    let mut resolved = HashMap::new();
+    for key in self.order.iter() {
+      try!(self.resolve(key, &mut Vec::new(), &mut resolved));
+    }
+    let keys = self.order.clone();
+    for key in keys.move_iter() {
+      let value = resolved.pop(&key).unwrap();
+      if !self.locked.contains(&key) {
+        self.replace(key, value);
+      }
+    }
+    Ok(())
+  }
+
+  fn resolve(&self, key: &String, resolving: &mut Vec<String>,
+             resolved: &mut HashMap<String, String>) -> Result<String, Error> {
+    match resolved.find(key) {
+      Some(value) => { return Ok(value.clone()) },
+      None => {}
+    }
+    if resolving.contains(key) {
+      return Err(CyclicReference(key.clone()))
+    }
+    let value = match self.store.find(key) {
+      Some(value) => { value.clone() },
+      None => { return Err(MissingReference(key.clone())) }
+    };
+
+    resolving.push(key.clone());
+    let mut result = String::new();
+    let mut rest = value.as_slice();
+    loop {
+      match rest.find_str("${") {
+        Some(start) if rest.slice_from(start).contains_char('}') => {
+          let end = start + rest.slice_from(start).find('}').unwrap();
+          let reference = self.key(rest.slice(start + 2, end));
+          result.push_str(rest.slice_to(start));
+          result.push_str(try!(self.resolve(&reference, resolving, resolved)).as_slice());
+          rest = rest.slice_from(end + 1);
+        },
+        _ => {
+          result.push_str(rest);
+          break
+        }
+      }
+    }
+    resolving.pop();
+
+    resolved.insert(key.clone(), result.clone());
+    Ok(result)
+  }
+
+  /// Remove the setting for a key. Returns the previously stored value, if any.
+  /// Locked settings are not removed, `None` is returned for them.
+  pub fn unset<A: Key>(&mut self, setting: A) -> Option<String> {
+    let key = self.key(setting);
+    if self.locked.contains(&key) {
+      return None
+    }
+    self.layers.pop(&key);
+    self.remove(&key)
+  }
+
+  /// Move the setting for a key to another key, along with its source and
+  /// layer. A setting already present under `to` is overwritten. Returns
+  /// whether there was a setting to move. Nothing is moved if either key is
+  /// locked.
+  pub fn rename<A: Key, B: Key>(&mut self, from: A, to: B) -> bool {
+    let from = self.key(from);
+    let to = self.key(to);
+    if self.locked.contains(&from) || self.locked.contains(&to) {
+      return false
+    }
+    let source = self.sources.find(&from).map(|source| source.clone());
+    let layer = self.layers.pop(&from);
+    match self.remove(&from) {
+      Some(value) => {
+        self.layers.pop(&to);
+        match layer {
+          Some(layer) => { self.layers.swap(to.clone(), layer); },
+          None => {}
+        }
+        self.insert(to, value, source.unwrap_or(ManualSource));
+        true
+      },
+      None => { false }
+    }
+  }
+
+  /// Remove all stored settings, except for locked ones. Registered command
+  /// line options are kept.
+  pub fn clear(&mut self) {
+    let keys: Vec<String> = self.order.iter()
+      .filter(|key| !self.locked.contains(*key)).map(|key| key.clone()).collect();
+    for key in keys.iter() {
+      self.layers.pop(key);
+      self.remove(key);
+    }
+  }
+
+  /// Capture all stored settings, along with their sources and layers, to
+  /// return to them later with `restore`. Registered options are not part of
+  /// the snapshot.
+  pub fn snapshot(&self) -> Snapshot {
+    Snapshot {
+      store: self.store.clone(),
+      order: self.order.clone(),
+      sources: self.sources.clone(),
+      layers: self.layers.clone(),
+    }
+  }
+
+  /// Replace all stored settings with the ones captured by `snapshot`.
+  /// Locked settings keep their current value, or stay absent.
+  pub fn restore(&mut self, snapshot: &Snapshot) {
+    let kept: Vec<(String, Option<(String, Source, Option<Layer>)>)> = self.locked.iter().map(|key| {
+      (key.clone(), self.store.find(key).map(|value| {
+        (value.clone(), self.sources.get(key).clone(), self.layers.find(key).map(|layer| layer.clone()))
+      }))
+    }).collect();
+    self.version = Version::new();
+    self.store = snapshot.store.clone();
+    self.order = snapshot.order.clone();
+    self.sources = snapshot.sources.clone();
+    self.layers = snapshot.layers.clone();
+    for (key, current) in kept.move_iter() {
+      self.layers.pop(&key);
+      match current {
+        Some((value, source, layer)) => {
+          if !self.store.contains_key(&key) {
+            self.order.push(key.clone());
+          }
+          self.sources.swap(key.clone(), source);
+          self.store.swap(key.clone(), value);
+          match layer {
+            Some(layer) => { self.layers.swap(key, layer); },
+            None => {}
+          }
+        },
+        None => { self.remove(&key); }
+      }
+    }
+  }
+
+  /// Check whether a setting is present for a key. The value is not parsed.
+  pub fn contains<A: Key>(&self, setting: A) -> bool {
+    self.store.contains_key(&self.key(setting))
+  }
+
+  /// Check whether settings are present for all of the given keys, wherever
+  /// they were loaded from. Returns the missing keys otherwise.
+  pub fn require(&self, settings: &[&str]) -> Result<(), Vec<String>> {
+    let missing: Vec<String> = settings.iter().map(|&setting| self.key(setting))
+      .filter(|key| !self.store.contains_key(key)).collect();
+    if missing.is_empty() {
+      Ok(())
+    } else {
+      Err(missing)
+    }
+  }
+
+  /// Fetch the raw string stored for a key, without parsing it.
+  pub fn get_raw<A: Key>(&self, setting: A) -> Option<String> {
+    self.store.find(&self.key(setting)).map(|value| value.clone())
+  }
+
+  /// Borrow the raw string stored for a key like `get_raw`, without copying it.
+  pub fn peek<'a, A: Key>(&'a self, setting: A) -> Option<&'a str> {
+    self.store.find(&self.key(setting)).map(|value| value.as_slice())
+  }
+
+  /// The source the setting for a key came from.
+  pub fn source_of<A: Key>(&self, setting: A) -> Option<Source> {
+    self.sources.find(&self.key(setting)).map(|source| source.clone())
+  }
+
+  /// The number of stored settings. This includes internal settings like
+  /// `knob.progname`, which is set by `load_args`.
+  pub fn len(&self) -> uint {
+    self.store.len()
+  }
+
+  /// Returns true if no settings are stored.
+  pub fn is_empty(&self) -> bool {
+    self.store.is_empty()
+  }
+
+  /// An iterator over all stored settings as pairs of keys and raw values, in
+  /// the order they were first set.
+  pub fn iter<'a>(&'a self) -> Entries<'a> {
+    Entries { store: &self.store, keys: self.order.iter() }
+  }
+
+  /// An iterator over the keys of all stored settings, in the order they were
+  /// first set.
+  pub fn keys<'a>(&'a self) -> Items<'a, String> {
+    self.order.iter()
+  }
+
+  /// All settings in the namespace `prefix`, i.e. with keys starting with the
+  /// prefix followed by a dot, like the ones set through `scope`. A setting
+  /// stored under the prefix itself is not part of the namespace. The prefix
+  /// and the dot are removed from the returned keys if `strip` is set.
+  pub fn fetch_prefix(&self, prefix: &str, strip: bool) -> HashMap<String, String> {
+    let namespace = format!("{}.", self.key(prefix.trim_right_chars('.')));
+    self.store.iter().filter(|&(key, _)| {
+      key.as_slice().starts_with(namespace.as_slice())
+    }).map(|(key, value)| {
+      let key = if strip {
+        key.as_slice().slice_from(namespace.len()).to_string()
+      } else {
+        key.clone()
+      };
+      (key, value.clone())
+    }).collect()
+  }
+
+  /// Fetch a setting for a key. Fails if the setting is present but could not be
+  /// parsed.
+  pub fn fetch<A: Key, T: FromStr>(&self, setting: A) -> Option<T> {
+    match self.try_fetch(setting) {
+      Ok(value) => { value },
+      Err(error) => { fail!("setting could not be parsed: {:?}", error.key) }
+    }
+  }
+
+  /// Fetch a setting for a key like `fetch`, but keep the parsed value in
+  /// `cache` to return it again on the next call, without parsing it again.
+  /// Use this for settings that are read often. The kept values are dropped
+  /// whenever a setting changes or the cache was last used with other
+  /// settings, so a cache never returns values of other settings.
+  pub fn fetch_cached<A: Key, T: FromStr + Clone + 'static>(&self, cache: &mut FetchCache, setting: A) -> Option<T> {
+    if cache.version != self.version.number {
+      cache.values.clear();
+      cache.version = self.version.number;
+    }
+    let key = self.key(setting);
+    let id = (key.clone(), TypeId::of::<T>());
+    match cache.values.find(&id).and_then(|value| value.as_ref::<T>()) {
+      Some(value) => { return Some(value.clone()) },
+      None => {}
+    }
+    let value: Option<T> = self.fetch(key);
+    match value {
+      Some(ref value) => { cache.values.swap(id, box value.clone() as Box<Any>); },
+      None => {}
+    }
+    value
+  }
+
+  /// Fetch a setting for a key as an `int`. Fails if the setting is present but
+  /// could not be parsed.
+  pub fn fetch_int<A: Key>(&self, setting: A) -> Option<int> {
+    self.fetch(setting)
+  }
+
+  /// Fetch a setting for a key as a `uint`. Fails if the setting is present but
+  /// could not be parsed.
+  pub fn fetch_uint<A: Key>(&self, setting: A) -> Option<uint> {
+    self.fetch(setting)
+  }
+
+  /// Fetch a setting for a key as an `f64`. Fails if the setting is present but
+  /// could not be parsed.
+  pub fn fetch_f64<A: Key>(&self, setting: A) -> Option<f64> {
+    self.fetch(setting)
+  }
+
+  /// Fetch a setting for a key as an `f64` like `fetch_f64`, but remove the
+  /// given unit from the end of the value first, like `Hz` from `44100 Hz`.
+  /// Values without the unit are parsed as they are.
+  pub fn fetch_f64_unit<A: Key>(&self, setting: A, unit: &str) -> Option<f64> {
+    let key = self.key(setting);
+    match self.lookup(&key) {
+      Some(string) => {
+        let string = string.as_slice();
+        let number = if string.ends_with(unit) {
+          string.slice_to(string.len() - unit.len()).trim_right()
+        } else {
+          string
+        };
+        match from_str(number) {
+          Some(value) => { Some(value) },
+          None => { fail!("setting could not be parsed: {:?}", key) }
+        }
+      },
+      None => { None }
+    }
+  }
+
+  /// Fetch a setting for a key as an integer, allowing underscores between
+  /// digits like in `1_000_000` and the prefixes `0x`, `0o` and `0b`. Returns
+  /// `None` if the setting is not present or is not an integer.
+  pub fn fetch_int_radix<A: Key>(&self, setting: A) -> Option<i64> {
+    self.fetch_parse(setting, |value| parse_int_radix(value))
+  }
+
+  /// Fetch a setting for a key as a `bool`, accepting the spellings understood
+  /// by `parse_bool`. Returns `None` if the setting is not present or is not a
+  /// boolean.
+  pub fn fetch_bool<A: Key>(&self, setting: A) -> Option<bool> {
+    self.fetch_parse(setting, |value| parse_bool(value))
+  }
+
+  /// Fetch a setting for a key as a `String`.
+  pub fn fetch_string<A: Key>(&self, setting: A) -> Option<String> {
+    self.fetch(setting)
+  }
+
+  /// Fetch a setting for a key as a `Duration`, written as a whole number with a
+  /// unit: `ms`, `s`, `m`, `h` or `d`, like `30s` or `500ms`. Returns `None` if
+  /// the setting is not present or is not a duration, including bare numbers
+  /// and durations too long to represent.
+  pub fn fetch_duration<A: Key>(&self, setting: A) -> Option<Duration> {
+    self.fetch_parse(setting, |value| parse_duration(value))
+  }
+
+  /// Fetch a setting for a key as one of the given values, by matching the
+  /// setting against their names, ignoring case. Returns `None` if the setting
+  /// is not present or matches no name.
+  pub fn fetch_enum<A: Key, T: Clone>(&self, setting: A, variants: &[(&str, T)]) -> Option<T> {
+    self.fetch_parse(setting, |value| {
+      variants.iter().find(|&&(name, _)| name.eq_ignore_ascii_case(value))
+        .map(|&(_, ref variant)| variant.clone())
+    })
+  }
+
+  /// Fetch a setting for a key as a `Path`.
+  pub fn fetch_path<A: Key>(&self, setting: A) -> Option<Path> {
+    self.lookup(&self.key(setting)).map(|value| Path::new(value))
+  }
+
+  /// Fetch a setting for a key as a `Path` like `fetch_path`, but with a
+  /// leading `~` expanded to the home directory. The value is kept as is if
+  /// the home directory is unknown.
+  pub fn fetch_path_expanded<A: Key>(&self, setting: A) -> Option<Path> {
+    self.lookup(&self.key(setting)).map(|value| {
+      let home = os::homedir();
+      if value.as_slice() == "~" && home.is_some() {
+        home.unwrap()
+      } else if value.as_slice().starts_with("~/") && home.is_some() {
+        home.unwrap().join(value.as_slice().slice_from(2))
+      } else {
+        Path::new(value)
+      }
+    })
+  }
+
+  /// Fetch a setting for a key without failing. Returns `Ok(None)` if the
+  /// setting is not present and a `ParseError` carrying the key and the raw
+  /// value if it could not be parsed.
+  pub fn try_fetch<A: Key, T: FromStr>(&self, setting: A) -> Result<Option<T>, ParseError> {
+    let key = self.key(setting);
+    match self.lookup(&key) {
+      Some(string) => {
+        match from_str(string.as_slice()) {
+          Some(value) => { Ok(Some(value)) },
+          None => {
+            self.parse_errors.increment();
+            Err(ParseError { key: key, value: string })
+          }
+        }
+      },
+      None => { Ok(None) }
+    }
+  }
+
+  /// The raw value to fetch for a key: the stored value, or the value of the
+  /// environment variable if the env fallback is enabled.
+  fn lookup(&self, key: &String) -> Option<String> {
+    match self.store.find(key) {
+      Some(value) => { return Some(value.clone()) },
+      None => {}
+    }
+    self.env_fallback.as_ref().and_then(|prefix| {
+      let name = if prefix.is_empty() {
+        key.as_slice().to_ascii_upper()
+      } else {
+        format!("{}_{}", prefix, key.as_slice().to_ascii_upper())
+      };
+      os::getenv(name.as_slice())
+    })
+  }
+
+  /// The number of times a stored setting could not be parsed when fetching
+  /// it, over the lifetime of the Settings struct.
+  pub fn parse_error_count(&self) -> uint {
+    self.parse_errors.get()
+  }
+
+  /// Fetch a setting for a key without failing, distinguishing missing from
+  /// invalid settings. Returns `Ok(None)` if the setting is not present and
+  /// the raw value as an error if it could not be parsed.
+  pub fn fetch_result<A: Key, T: FromStr>(&self, setting: A) -> Result<Option<T>, String> {
+    self.try_fetch(setting).map_err(|error| error.value)
+  }
+
+  /// Check the settings for the given keys with their `TypeCheck`s and return
+  /// the key and raw value of every setting that fails its check. Settings that
+  /// are not present are skipped.
+  pub fn validate_types(&self, specs: &[(&str, TypeCheck)]) -> Vec<(String, String)> {
+    specs.iter().filter_map(|&(setting, check)| {
+      let key = self.key(setting);
+      match self.store.find(&key) {
+        Some(value) if !check(value.as_slice()) => { Some((key.clone(), value.clone())) },
+        _ => { None }
+      }
+    }).collect()
+  }
+
+  /// Fetch a setting for a key, returning `default` if it is not present.
+  ///
+  /// Unlike `fetch`, this does not fail if the setting is present but could not
+  /// be parsed. The default is returned in that case as well.
+  pub fn fetch_or<A: Key, T: FromStr>(&self, setting: A, default: T) -> T {
+    match self.try_fetch(setting) {
+      Ok(Some(value)) => { value },
+      _ => { default }
+    }
+  }
+
+  /// Fetch a setting that is required to be present and to parse. Returns a
+  /// `Missing` error if it is not present and a `Parse` error carrying the raw
+  /// value if it could not be parsed.
+  pub fn get<A: Key, T: FromStr>(&self, setting: A) -> Result<T, Error> {
+    let key = self.key(setting);
+    match self.try_fetch(key.as_slice()) {
+      Ok(Some(value)) => { Ok(value) },
+      Ok(None) => { Err(Missing(key)) },
+      Err(error) => { Err(Parse(error.key, error.value)) }
+    }
+  }
+
+  /// Fetch a setting that is required to be present and to parse like `get`,
+  /// but with an error message ready to be printed, like `missing required
+  /// setting 'port'`.
+  pub fn require_get<A: Key, T: FromStr>(&self, setting: A) -> Result<T, String> {
+    self.get(setting).map_err(|error| {
+      match error {
+        Missing(key) => { format!("missing required setting '{}'", key) },
+        Parse(key, value) => { format!("invalid value for '{}': '{}'", key, value) },
+        error => { error.to_str() }
+      }
+    })
+  }
+
+  /// Fetch a setting for a dotted key, falling back to the enclosing
+  /// namespaces if it is not present. For `db.replica.port`, this tries
+  /// `db.replica.port`, `db.port` and `port`, in that order. Fails if the first
+  /// setting present could not be parsed.
+  pub fn fetch_hierarchical<T: FromStr>(&self, setting: &str) -> Option<T> {
+    let mut namespace: Vec<&str> = setting.split('.').collect();
+    let name = namespace.pop().unwrap();
+    loop {
+      let key = if namespace.is_empty() {
+        name.to_string()
+      } else {
+        format!("{}.{}", namespace.connect("."), name)
+      };
+      if self.contains(key.as_slice()) || namespace.is_empty() {
+        return self.fetch(key)
+      }
+      namespace.pop();
+    }
+  }
+
+  /// Fetch the first setting that is present and can be parsed, trying the
+  /// keys in order. Useful for settings that were renamed, with the legacy
+  /// names as fallbacks.
+  pub fn fetch_any<T: FromStr>(&self, settings: &[&str]) -> Option<T> {
+    for setting in settings.iter() {
+      match self.try_fetch(*setting) {
+        Ok(Some(value)) => { return Some(value) },
+        _ => {}
+      }
+    }
+    None
+  }
+
+  /// Fetch a setting for a key, storing and returning `default` if it is not
+  /// present. Fails if the setting is present but could not be parsed.
+  pub fn fetch_or_set<A: Key, T: FromStr + ToStr>(&mut self, setting: A, default: T) -> T {
+    let key = self.key(setting);
+    match self.fetch(key.as_slice()) {
+      Some(value) => { value },
+      None => {
+        self.set(key, default.to_str());
+        default
+      }
+    }
+  }
+
+  /// Fetch a list setting for a key, as stored by `set_list` or for repeated
+  /// command line options. Fails if an element could not be parsed.
+  pub fn fetch_list<A: Key, T: FromStr>(&self, setting: A) -> Option<Vec<T>> {
+    let key = self.key(setting);
+    match self.lookup(&key) {
+      Some(ref string) if string.is_empty() => { Some(Vec::new()) },
+      Some(string) => {
+        Some(string.as_slice().split_str(LIST_SEPARATOR).map(|element| {
+          match from_str(element) {
+            Some(value) => { value },
+            None => { fail!("setting could not be parsed: {:?}", key) }
+          }
+        }).collect())
+      },
+      None => { None }
+    }
+  }
+
+  /// Fetch a setting for a key as a comma-separated list, like `a, b, c`.
+  /// Whitespace around the elements is removed and empty elements are skipped.
+  /// Fails if an element could not be parsed.
+  pub fn fetch_csv<A: Key, T: FromStr>(&self, setting: A) -> Option<Vec<T>> {
+    self.fetch_list_sep(setting, [','])
+  }
+
+  /// Fetch a setting for a key as a list separated by any of the given
+  /// characters, like `[',', ';', ' ']`. Whitespace around the elements is
+  /// removed and empty elements are skipped. Fails if an element could not be
+  /// parsed.
+  pub fn fetch_list_sep<A: Key, T: FromStr>(&self, setting: A, separators: &[char]) -> Option<Vec<T>> {
+    let key = self.key(setting);
+    self.lookup(&key).map(|string| {
+      string.as_slice().split(separators).map(|element| element.trim())
+        .filter(|element| !element.is_empty())
+        .map(|element| {
+          match from_str(element) {
+            Some(value) => { value },
+            None => { fail!("setting could not be parsed: {:?}", key) }
+          }
+        }).collect()
+    })
+  }
+
+  /// Fetch a setting for a key and pass it to the given function. The result of the function
+  /// will be returned.
+  pub fn fetch_with<A: Key, T: FromStr>(&self, setting: A, f: |Option<T>| -> T) -> T {
+    let value = self.fetch(setting);
+    f(value)
+  }
+
+  /// Fetch a setting for a key and pass it to the given function like
+  /// `fetch_with`, but without failing if the setting could not be parsed. The
+  /// function is passed the raw value as an error in that case, and `Ok(None)`
+  /// if the setting is not present.
+  pub fn fetch_with_result<A: Key, T: FromStr>(&self, setting: A,
+                                                 f: |Result<Option<T>, String>| -> T) -> T {
+    let value = self.try_fetch(setting).map_err(|error| error.value);
+    f(value)
+  }
+
+  /// Fetch a setting for a key and pass it to the given function, which may
+  /// derive a value of any type from it. The result of the function will be
+  /// returned.
+  pub fn fetch_map<A: Key, T: FromStr, U>(&self, setting: A, f: |Option<T>| -> U) -> U {
+    let value = self.fetch(setting);
+    f(value)
+  }
+
+  /// Fetch a setting for a key and parse it with the given function instead of
+  /// `FromStr`.
+  pub fn fetch_parse<A: Key, T>(&self, setting: A, parse: |&str| -> Option<T>) -> Option<T> {
+    match self.lookup(&self.key(setting)) {
+      Some(string) => { parse(string.as_slice()) },
+      None => { None }
+    }
+  }
+
+  /// Fetch a setting for a key, passing it through the given function before
+  /// parsing it with `FromStr`, for example to strip a prefix. Returns `None`
+  /// if the setting is not present or the normalized value could not be
+  /// parsed.
+  pub fn fetch_normalized<A: Key, T: FromStr>(&self, setting: A, normalize: |&str| -> String) -> Option<T> {
+    match self.lookup(&self.key(setting)) {
+      Some(string) => { from_str(normalize(string.as_slice()).as_slice()) },
+      None => { None }
+    }
+  }
+
+  /// Load settings from a simple config file into the `FileLayer`.
+  ///
+  /// Every line holds a `key=value` pair, split at the first `=`. Surrounding
+  /// whitespace is removed from both key and value. Blank lines and lines starting
+  /// with `#` are skipped.
+  ///
+  /// In strict mode, a key that is not allowed is reported as an error. The
+  /// settings before it are kept.
+  pub fn load_file(&mut self, path: &Path) -> IoResult<()> {
+    let mut file = try!(File::open(path));
+    self.load_reader(&mut file)
+  }
+
+  /// Load settings from several config files in order, like a system-wide
+  /// file followed by a user's file. Settings from later files override the
+  /// ones from earlier files.
+  ///
+  /// Files that fail to load, for example because they don't exist, don't stop
+  /// the remaining files from loading. The result for every file is returned.
+  pub fn load_files(&mut self, paths: &[Path]) -> Vec<(Path, IoResult<()>)> {
+    paths.iter().map(|path| (path.clone(), self.load_file(path))).collect()
+  }
+
+  /// Load settings from a config file like `load_file`, reporting the
+  /// settings that were already present and got a different value. The
+  /// changes are returned as `(key, old value, new value)`, in the order the
+  /// settings were first set.
+  pub fn load_file_reporting(&mut self, path: &Path) -> IoResult<Vec<(String, String, String)>> {
+    let previous = self.store.clone();
+    try!(self.load_file(path));
+    Ok(self.order.iter().filter_map(|key| {
+      match (previous.find(key), self.store.find(key)) {
+        (Some(old), Some(new)) if old != new => { Some((key.clone(), old.clone(), new.clone())) },
+        _ => { None }
+      }
+    }).collect())
+  }
+
+  /// Load settings in the format read by `load_file` from a reader, like
+  /// standard input or an in-memory buffer, into the `FileLayer`.
+  pub fn load_reader<R: Reader>(&mut self, reader: &mut R) -> IoResult<()> {
+    let contents = try!(reader.read_to_str());
+    for line in contents.as_slice().lines() {
+      let line = line.trim();
+      if line.is_empty() || line.starts_with("#") {
+        continue
+      }
+      let mut parts = line.splitn('=', 1);
+      let key = parts.next().unwrap().trim();
+      if !self.is_allowed(key) {
+        return Err(IoError {
+          kind: InvalidInput,
+          desc: "unknown key",
+          detail: Some(key.to_string())
+        })
+      }
+      match parts.next() {
+        Some(value) => {
+          match self.try_set_layered(key, value.trim(), FileLayer) {
+            Ok(()) => {},
+            Err(error) => {
+              return Err(IoError {
+                kind: InvalidInput,
+                desc: "invalid value",
+                detail: Some(error.to_str())
+              })
+            }
+          }
+        },
+        None => {
+          return Err(IoError {
+            kind: InvalidInput,
+            desc: "line is not a key=value pair",
+            detail: Some(line.to_string())
+          })
+        }
+      }
+    }
+    Ok(())
+  }
+
+  /// Save all settings to a config file readable by `load_file`.
+  ///
+  /// The settings are written as `key=value` lines, sorted by key. Internal keys
+  /// like `knob.progname` are not written.
+  pub fn save_file(&self, path: &Path) -> IoResult<()> {
+    self.save_file_with_header(path, "")
+  }
+
+  /// Format all settings as a table of keys and values for printing, sorted by
+  /// key, with the values aligned in a column. Values longer than `width`
+  /// characters are cut short with `...`, or without it if `width` is less
+  /// than 3. Internal keys like `knob.progname` are left out.
+  pub fn format_table(&self, width: uint) -> String {
+    let mut keys: Vec<&String> = self.store.keys()
+      .filter(|key| !is_internal(key.as_slice()))
+      .collect();
+    keys.sort();
+    let key_width = keys.iter().map(|key| key.as_slice().char_len()).max().unwrap_or(0);
+    let mut table = String::new();
+    for key in keys.iter() {
+      let value = self.store.get(*key).as_slice();
+      table.push_str(key.as_slice());
+      table.push_str(" ".repeat(key_width - key.as_slice().char_len() + 2).as_slice());
+      if value.char_len() > width && width < 3 {
+        let shortened: String = value.chars().take(width).collect();
+        table.push_str(shortened.as_slice());
+      } else if value.char_len() > width {
+        let shortened: String = value.chars().take(width - 3).collect();
+        table.push_str(shortened.as_slice());
+        table.push_str("...");
+      } else {
+        table.push_str(value);
+      }
+      table.push_char('\n');
+    }
+    table
+  }
+
+  /// Save all settings to a config file like `save_file`, starting with a
+  /// header. Every line of the header is written as a comment, followed by a
+  /// blank line.
+  pub fn save_file_with_header(&self, path: &Path, header: &str) -> IoResult<()> {
+    let mut file = try!(File::create(path));
+    for line in header.lines() {
+      if line.is_empty() {
+        try!(write!(file, "#\n"));
+      } else {
+        try!(write!(file, "# {}\n", line));
+      }
+    }
+    if !header.is_empty() {
+      try!(write!(file, "\n"));
+    }
+    let mut keys: Vec<&String> = self.store.keys()
+      .filter(|key| !is_internal(key.as_slice()))
+      .collect();
+    keys.sort();
+    for key in keys.iter() {
+      try!(write!(file, "{}={}\n", key, self.store.get(*key)));
+    }
+    Ok(())
+  }
+
+  /// Save the settings that differ from `defaults` to a config file like
+  /// `save_file`. Settings that are not present in `defaults` are always
+  /// written.
+  pub fn save_overrides(&self, path: &Path, defaults: &Settings) -> IoResult<()> {
+    let mut overrides = self.clone();
+    for (key, value) in defaults.store.iter() {
+      if overrides.store.find(key) == Some(value) {
+        overrides.remove(key);
+      }
+    }
+    overrides.save_file(path)
+  }
+
+  /// Load settings from a `.env` file into the `EnvLayer`.
+  ///
+  /// Every line holds a `KEY=value` pair, optionally prefixed with `export`.
+  /// Matching quotes around values are removed and comments are skipped, unless
+  /// the `#` is within quotes. Keys are stored as they are, without lowercasing.
+  /// In strict mode, loading stops at the first key that is not allowed.
+  pub fn load_dotenv(&mut self, path: &Path) -> IoResult<()> {
+    let contents = try!(File::open(path).read_to_str());
+    let pairs = match dotenv::parse(contents.as_slice()) {
+      Ok(pairs) => { pairs },
+      Err(line) => {
+        return Err(IoError {
+          kind: InvalidInput,
+          desc: "line is not a KEY=value pair",
+          detail: Some(line)
+        })
+      }
+    };
+    for (key, value) in pairs.move_iter() {
+      if !self.is_allowed(key.as_slice()) {
+        return Err(IoError {
+          kind: InvalidInput,
+          desc: "unknown key",
+          detail: Some(key)
+        })
+      }
+      match self.try_set_layered(key, value, EnvLayer) {
+        Ok(()) => {},
+        Err(error) => {
+          return Err(IoError {
+            kind: InvalidInput,
+            desc: "invalid value",
+            detail: Some(error.to_str())
+          })
+        }
+      }
+    }
+    Ok(())
+  }
+
+  /// Export all settings as a flat JSON object of strings, sorted by key.
+  ///
+  /// Internal keys like `knob.progname` are only included if `include_internal`
+  /// is set.
+  pub fn to_json(&self, include_internal: bool) -> String {
+    let mut object = TreeMap::new();
+    for (key, value) in self.store.iter() {
+      if include_internal || !is_internal(key.as_slice()) {
+        object.insert(key.clone(), json::String(value.clone()));
+      }
+    }
+    json::Object(box object).to_str()
+  }
+
+  /// Load settings from a flat JSON object into the `FileLayer`.
+  ///
+  /// Numbers and booleans are stored in their string representation. Any other
+  /// value, like nested objects or lists, is rejected and nothing is stored. So
+  /// is a key that is not allowed in strict mode.
+  pub fn load_json(&mut self, input: &str) -> Result<(), Error> {
+    let object = match json::from_str(input) {
+      Ok(json::Object(object)) => { object },
+      Ok(_) => { return Err(InvalidJson("expected an object".to_string())) },
+      Err(error) => { return Err(InvalidJson(error.to_str())) }
+    };
+    let mut pairs = Vec::new();
+    for (key, value) in object.iter() {
+      if !self.is_allowed(key.as_slice()) {
+        return Err(UnknownKey(key.clone()))
+      }
+      let value = match *value {
+        json::String(ref string) => { string.clone() },
+        json::Number(_) | json::Boolean(_) => { value.to_str() },
+        _ => { return Err(UnsupportedValue(key.clone())) }
+      };
+      pairs.push((key.clone(), value));
+    }
+    for (key, value) in pairs.move_iter() {
+      try!(self.try_set_layered(key, value, FileLayer));
+    }
+    Ok(())
+  }
+
+  /// Load settings from a flat TOML document into the `FileLayer`.
+  ///
+  /// Keys within a `[section]` are stored as `section.key`. Strings are
+  /// unquoted, all other values are stored as written. Arrays, inline tables
+  /// and arrays of tables are rejected and nothing is stored. So is a key that
+  /// is not allowed in strict mode.
+  pub fn load_toml(&mut self, input: &str) -> Result<(), Error> {
+    let pairs = try!(toml::parse(input));
+    for &(ref key, _) in pairs.iter() {
+      if !self.is_allowed(key.as_slice()) {
+        return Err(UnknownKey(key.clone()))
+      }
+    }
+    for (key, value) in pairs.move_iter() {
+      try!(self.try_set_layered(key, value, FileLayer));
+    }
+    Ok(())
+  }
+
+  /// Load settings from a YAML mapping of scalars into the `FileLayer`.
+  ///
+  /// Keys of a nested mapping are stored as `parent.child`. Quoted strings are
+  /// unquoted, all other values are stored as written. Sequences, deeper
+  /// nesting and other YAML features are rejected and nothing is stored. So is
+  /// a key that is not allowed in strict mode.
+  pub fn load_yaml(&mut self, input: &str) -> Result<(), Error> {
+    let pairs = try!(yaml::parse(input));
+    for &(ref key, _) in pairs.iter() {
+      if !self.is_allowed(key.as_slice()) {
+        return Err(UnknownKey(key.clone()))
+      }
+    }
+    for (key, value) in pairs.move_iter() {
+      try!(self.try_set_layered(key, value, FileLayer));
+    }
+    Ok(())
+  }
+
+  /// Load settings from the environment into the `EnvLayer`.
+  ///
+  /// Only variables named `PREFIX_KEY` are loaded. The prefix is stripped and
+  /// the remaining name is lowercased, so `MYAPP_PORT` is stored as `port` for the
+  /// prefix `MYAPP`. An empty prefix loads every variable.
+  ///
+  /// Returns an error if a value is rejected by its validation rule.
+  pub fn load_env(&mut self, prefix: &str) -> Result<(), Error> {
+    let prefix = if prefix.is_empty() { String::new() } else { format!("{}_", prefix) };
+    for (name, value) in os::env().move_iter() {
+      if name.as_slice().starts_with(prefix.as_slice()) {
+        let key = name.as_slice().slice_from(prefix.len()).to_ascii_lower();
+        try!(self.try_set_layered(key, value, EnvLayer));
+      }
+    }
+    Ok(())
+  }
+
+  /// Load `key=value` assignments given as command line arguments, like
+  /// `db.host=localhost`, into the `CliLayer`. Arguments are split at the
+  /// first `=`; arguments without one or with an empty key are skipped. Use
+  /// this on the free arguments left by `load_args`.
+  ///
+  /// Returns an error if a value is rejected by its validation rule.
+  pub fn load_assignments(&mut self, args: &[String]) -> Result<(), Error> {
+    for arg in args.iter() {
+      let mut parts = arg.as_slice().splitn('=', 1);
+      let key = parts.next().unwrap();
+      match parts.next() {
+        Some(value) if !key.is_empty() => { try!(self.try_set_layered(key, value, CliLayer)) },
+        _ => {}
+      }
+    }
+    Ok(())
+  }
+
+  /// Register a commandline for later use with load_args.
+  ///
+  /// Currently, only optopt, reqopt, optmulti, optflag and optflagopt are properly
+  /// supported. Flags are stored as "true" if given and left unset otherwise. All
+  /// occurrences of an optmulti are stored as a list, to be read with `fetch_list`.
+  ///
+  /// An optflagopt stores its value if one is given, like `--color=always`. If it
+  /// is given without a value, like `--color`, it is stored as "true", just like
+  /// a flag.
+  ///
+  /// Values are stored under the long name of the option, or under its short
+  /// name if it has no long name.
+  pub fn opt(&mut self, opt: OptGroup) {
+    self.options.push(opt);
+  }
+
+  /// Register a commandline for later use with load_args, which stores its value
+  /// under the key `canonical` instead of its long name. This way, several
+  /// options can set the same key. If more than one of them is given, the one
+  /// given last on the command line wins.
+  pub fn opt_alias(&mut self, opt: OptGroup, canonical: &str) {
+    let long_name = self.key(opt.long_name.as_slice());
+    let canonical = self.key(canonical);
+    self.aliases.swap(long_name, canonical);
+    self.options.push(opt);
+  }
+
+  /// Register several commandlines for later use with load_args, declared in a
+  /// table instead of with getopts' functions.
+  pub fn add_options(&mut self, specs: &[OptSpec]) {
+    for spec in specs.iter() {
+      self.options.push(OptGroup {
+        short_name: spec.short.to_string(),
+        long_name: spec.long.to_string(),
+        hint: spec.hint.to_string(),
+        desc: spec.desc.to_string(),
+        hasarg: match spec.kind {
+          FlagKind => { No },
+          ValueKind | MultiKind => { Yes },
+          FlagValueKind => { Maybe }
+        },
+        occur: match spec.kind {
+          MultiKind => { Multi },
+          _ if spec.required => { Req },
+          _ => { Optional }
+        }
+      });
+    }
+  }
+
+  /// Register a commandline for later use with load_args, along with a default
+  /// value. If the option is not given and no setting is present for it yet,
+  /// load_args stores the default in the `DefaultLayer`, so values from any
+  /// other source win over it.
+  pub fn opt_default(&mut self, opt: OptGroup, default: &str) {
+    let long_name = self.key(opt.long_name.as_slice());
+    self.opt_defaults.swap(long_name, default.to_string());
+    self.options.push(opt);
+  }
+
+  /// Suggest the long name of a registered option that is close to the given,
+  /// possibly misspelled, name. Use this to improve the message for an
+  /// `UnrecognizedOption` error.
+  pub fn suggest_option(&self, name: &str) -> Option<String> {
+    let name = name.trim_left_chars('-');
+    let threshold = cmp::max(2, name.char_len() / 3);
+    self.options.iter()
+      .filter(|opt| !opt.long_name.is_empty())
+      .map(|opt| (edit_distance(name, opt.long_name.as_slice()), opt))
+      .filter(|&(distance, _)| distance <= threshold)
+      .min_by(|&(distance, _)| distance)
+      .map(|(_, opt)| opt.long_name.clone())
+  }
+
+  /// The long names of the registered options that have no stored setting,
+  /// neither from the command line nor from any other source. Negations
+  /// registered by `opt_bool` are not reported.
+  pub fn unset_options(&self) -> Vec<String> {
+    self.options.iter().filter(|opt| {
+      let long_name = opt.long_name.as_slice();
+      let negated = long_name.starts_with("no-") &&
+        self.negatable.contains(&self.key(long_name.slice_from(3)));
+      !long_name.is_empty() && !negated
+    }).filter(|opt| !self.contains(self.option_key(*opt))).map(|opt| opt.long_name.clone()).collect()
+  }
+
+  /// The long names of the registered options that were given on the command
+  /// line in the last call to load_args, regardless of defaults or values from
+  /// other sources.
+  pub fn provided_options(&self) -> Vec<String> {
+    self.provided.clone()
+  }
+
+  /// The key the value of a registered option is stored under: the canonical
+  /// key of an alias, or else the long name, or the short name if the option
+  /// has no long name.
+  fn option_key(&self, opt: &OptGroup) -> String {
+    match self.aliases.find(&self.key(opt.long_name.as_slice())) {
+      Some(canonical) => { canonical.clone() },
+      None if opt.long_name.is_empty() => { self.key(opt.short_name.as_slice()) },
+      None => { self.key(opt.long_name.as_slice()) }
+    }
+  }
+
+  /// Register a boolean flag for later use with load_args, along with its
+  /// negation. `--long` stores "true" and `--no-long` stores "false" under the
+  /// key `long`. If both are given, the last one wins.
+  pub fn opt_bool(&mut self, short_name: &str, long_name: &str, desc: &str) {
+    self.options.push(optflag(short_name, long_name, desc));
+    self.options.push(optflag("", format!("no-{}", long_name).as_slice(),
+                              format!("Negate --{}", long_name).as_slice()));
+    let long_name = self.key(long_name);
+    self.negatable.push(long_name);
+  }
+
+  /// Load the command line argument given by the OS.
+  ///
+  /// Returns an `Error` if the arguments could not be parsed.
+  pub fn load_os_args(&mut self) -> Result<(), Error> {
+    self.load_args(os::args())
+  }
+
+  /// Load a list of command line arguments into the `CliLayer`.
+  ///
+  /// Automatically sets "knob.progname" to the name of the program and
+  /// "knob.free" to the list of free arguments.
+  ///
+  /// Returns an `Error` if the arguments could not be parsed.
+  pub fn load_args(&mut self, args: Vec<String>) -> Result<(), Error> {
+    self.load_args_keeping(args, false)
+  }
+
+  /// Load a list of command line arguments like `load_args`, but only set keys
+  /// that are not present yet. This way, settings loaded from files or the
+  /// environment take precedence over the command line.
+  pub fn load_args_if_absent(&mut self, args: Vec<String>) -> Result<(), Error> {
+    self.load_args_keeping(args, true)
+  }
+
+  fn load_args_keeping(&mut self, args: Vec<String>, keep_present: bool) -> Result<(), Error> {
+    let ref prog_name = args.get(0);
+
+    self.set(PROGNAME, prog_name.clone());
+
+    let matches = match getopts(args.tail(), self.options.as_slice()) {
+      Ok(m) => { m }
+      Err(fail) => { return Err(Error::from_fail(fail)) }
+    };
+
+    self.store_matches(args.as_slice(), &matches, keep_present)
+  }
+
+  /// Load a list of command line arguments like `load_args`, but report every
+  /// missing required option instead of only the first one.
+  ///
+  /// Other failures are still reported one at a time.
+  pub fn load_args_all(&mut self, args: Vec<String>) -> Result<(), Vec<Error>> {
+    let ref prog_name = args.get(0);
+
+    self.set(PROGNAME, prog_name.clone());
+
+    let relaxed: Vec<OptGroup> = self.options.iter().map(|opt| {
+      let mut opt = opt.clone();
+      if opt.occur == Req {
+        opt.occur = Optional;
+      }
+      opt
+    }).collect();
+
+    let matches = match getopts(args.tail(), relaxed.as_slice()) {
+      Ok(m) => { m }
+      Err(fail) => { return Err(vec!(Error::from_fail(fail))) }
+    };
+
+    let missing: Vec<Error> = self.options.iter().filter(|opt| {
+      opt.occur == Req && !matches.opts_present(&[opt.short_name.clone(), opt.long_name.clone()])
+    }).map(|opt| {
+      let name = if opt.long_name.is_empty() { opt.short_name.clone() } else { opt.long_name.clone() };
+      OptionMissing(name)
+    }).collect();
+    if !missing.is_empty() {
+      return Err(missing)
+    }
+
+    self.store_matches(args.as_slice(), &matches, false).map_err(|error| vec!(error))
+  }
+
+  fn store_matches(&mut self, args: &[String], matches: &Matches,
+                   keep_present: bool) -> Result<(), Error> {
+    self.provided = self.options.iter().filter(|opt| {
+      !opt.long_name.is_empty() &&
+        matches.opts_present(&[opt.short_name.clone(), opt.long_name.clone()])
+    }).map(|opt| opt.long_name.clone()).collect();
+    let values: Vec<(String, String, Layer)> = self.options.iter().filter_map(|opt| {
+      let opt_strings = &[opt.short_name.clone(), opt.long_name.clone()];
+      let name = if opt.long_name.is_empty() { opt.short_name.clone() } else { opt.long_name.clone() };
+      let negated = opt.long_name.as_slice().starts_with("no-") &&
+        self.negatable.contains(&self.key(opt.long_name.as_slice().slice_from(3)));
+      let value = if negated {
+        None
+      } else if self.negatable.contains(&self.key(opt.long_name.as_slice())) {
+        let negation = format!("no-{}", opt.long_name);
+        let given = last_position(args, self.options.as_slice(), opt.short_name.as_slice(),
+                                  opt.long_name.as_slice());
+        let negation_given = last_position(args, self.options.as_slice(), "", negation.as_slice());
+        match (given, negation_given) {
+          (None, None) => { None },
+          (Some(position), Some(negation_position)) if position > negation_position => {
+            Some("true".to_string())
+          },
+          (Some(_), None) => { Some("true".to_string()) },
+          _ => { Some("false".to_string()) }
+        }
+      } else if opt.occur == Multi {
+        let values = matches.opt_strs(name.as_slice());
+        if values.is_empty() { None } else { Some(values.connect(LIST_SEPARATOR)) }
+      } else {
+        match opt.hasarg {
+          No => {
+            if matches.opts_present(opt_strings) { Some("true".to_string()) } else { None }
+          },
+          Maybe => {
+            if matches.opts_present(opt_strings) {
+              Some(matches.opts_str(opt_strings).unwrap_or("true".to_string()))
+            } else {
+              None
+            }
+          },
+          _ => { matches.opts_str(opt_strings) }
+        }
+      };
+      let key = self.option_key(opt);
+      let value = if value.is_some() && self.aliases.values().any(|canonical| *canonical == key) {
+        let position = last_position(args, self.options.as_slice(), opt.short_name.as_slice(),
+                                     opt.long_name.as_slice());
+        let given_later = self.options.iter().any(|other| {
+          self.option_key(other) == key &&
+            last_position(args, self.options.as_slice(), other.short_name.as_slice(),
+                          other.long_name.as_slice()) > position
+        });
+        if given_later { None } else { value }
+      } else {
+        value
+      };
+      match value {
+        Some(value) => { Some((key, value, CliLayer)) },
+        None if !self.contains(key.as_slice()) => {
+          self.opt_defaults.find(&self.key(opt.long_name.as_slice()))
+            .map(|default| (key, default.clone(), DefaultLayer))
+        },
+        None => { None }
+      }
+    }).collect();
+    for (key, value, layer) in values.move_iter() {
+      if keep_present && self.contains(key.as_slice()) {
+        continue
+      }
+      try!(self.try_set_layered(key, value, layer));
+    }
+    self.try_set_layered(FREE_ARGS, matches.free.connect(LIST_SEPARATOR), CliLayer)
+  }
+
+  /// The name of the program, as given by the first argument passed to
+  /// `load_args`.
+  pub fn progname(&self) -> Option<String> {
+    self.get_raw(PROGNAME)
+  }
+
+  /// The free arguments, which are not options or their values, as given to
+  /// `load_args`.
+  pub fn free_args(&self) -> Vec<String> {
+    self.fetch_list(FREE_ARGS).unwrap_or(Vec::new())
+  }
+
+  /// Freeze the settings, usually after loading them, so they can't be changed
+  /// anymore.
+  pub fn freeze(self) -> FrozenSettings {
+    FrozenSettings { settings: self }
+  }
+
+  /// Consume the settings, returning the raw stored values by key. Registered
+  /// options are dropped. Internal keys like `knob.progname` are included.
+  pub fn into_map(self) -> HashMap<String, String> {
+    self.store
+  }
+
+  /// Returns the usage string for the stored OptGroups. Pass `brief`
+  /// to have a brief message included before the usage strings.
+  ///
+  /// Without any OptGroups, only the brief message is returned.
+  pub fn usage(&self, brief: String) -> String {
+    if self.options.is_empty() {
+      return brief
+    }
+    usage(brief.as_slice(), self.options.as_slice())
+  }
+
+  /// Returns the usage string for the stored OptGroups without a brief
+  /// message. Without any OptGroups, the usage string is empty.
+  pub fn usage_brief(&self) -> String {
+    self.usage(String::new())
+  }
+
+  /// Returns the usage string for the stored OptGroups like `usage`, but with
+  /// the option descriptions wrapped to fit into `width` columns. The
+  /// descriptions stay aligned in a column of their own.
+  ///
+  /// Lines only exceed `width` if a single word does not fit or the width is too
+  /// small to leave room for the descriptions.
+  pub fn usage_wrapped(&self, brief: &str, width: uint) -> String {
+    if self.options.is_empty() {
+      return brief.to_string()
+    }
+    let description_width = if width > DESCRIPTION_COLUMN + 10 {
+      width - DESCRIPTION_COLUMN
+    } else {
+      10
+    };
+    let indent = " ".repeat(DESCRIPTION_COLUMN);
+
+    let rows: Vec<String> = self.options.iter().map(|opt| {
+      let mut row = "    ".to_string();
+      if !opt.short_name.is_empty() {
+        row.push_str(format!("-{} ", opt.short_name).as_slice());
+      }
+      if !opt.long_name.is_empty() {
+        row.push_str(format!("--{} ", opt.long_name).as_slice());
+      }
+      match opt.hasarg {
+        No => {},
+        Maybe => { row.push_str(format!("[{}]", opt.hint).as_slice()) },
+        _ => { row.push_str(opt.hint.as_slice()) }
+      }
+      if row.len() < DESCRIPTION_COLUMN {
+        row.push_str(" ".repeat(DESCRIPTION_COLUMN - row.len()).as_slice());
+      } else {
+        row.push_char('\n');
+        row.push_str(indent.as_slice());
+      }
+      let separator = format!("\n{}", indent);
+      let description = wrap_words(opt.desc.as_slice(), description_width);
+      row.push_str(description.connect(separator.as_slice()).as_slice());
+      row
+    }).collect();
+
+    format!("{}\n\nOptions:\n{}\n", brief, rows.connect("\n"))
+  }
+
+  /// Describes the stored OptGroups, in the order they were registered. Use
+  /// this to render help screens other than the one given by `usage`.
+  pub fn describe_options(&self) -> Vec<OptionInfo> {
+    self.options.iter().map(|opt| {
+      OptionInfo {
+        short: opt.short_name.clone(),
+        long: opt.long_name.clone(),
+        description: opt.desc.clone(),
+        hint: opt.hint.clone(),
+        required: opt.occur == Req,
+      }
+    }).collect()
+  }
+
+  /// Returns a bash completion script completing the stored options for the
+  /// program `prog`. Options without a short name only complete in their
+  /// long form.
+  pub fn bash_completion(&self, prog: &str) -> String {
+    let mut words = Vec::new();
+    for opt in self.describe_options().iter() {
+      if !opt.long.is_empty() {
+        words.push(format!("--{}", opt.long));
+      }
+      if !opt.short.is_empty() {
+        words.push(format!("-{}", opt.short));
+      }
+    }
+    let function: String = prog.chars().map(|c| {
+      if c.is_alphanumeric() { c } else { '_' }
+    }).collect();
+
+    format!("_{function}() {{\n  \
+             local cur=\"${{COMP_WORDS[COMP_CWORD]}}\"\n  \
+             COMPREPLY=( $(compgen -W \"{words}\" -- \"$cur\") )\n\
+             }}\n\
+             complete -F _{function} {prog}\n",
+            function = function, words = words.connect(" "), prog = prog)
+  }
+}
+
+impl Default for Settings {
+  fn default() -> Settings {
+    Settings::new()
+  }
+}
+
+impl<A: Key, T: ToStr> FromIterator<(A, T)> for Settings {
+  fn from_iter<I: Iterator<(A, T)>>(iter: I) -> Settings {
+    let mut settings = Settings::new();
+    settings.extend(iter);
+    settings
+  }
+}
+
+/// An iterator over the settings in a `Settings` struct, in the order they
+/// were first set.
+pub struct Entries<'a> {
+  store: &'a HashMap<String,String>,
+  keys: Items<'a, String>,
+}
+
+impl<'a> Iterator<(&'a String, &'a String)> for Entries<'a> {
+  fn next(&mut self) -> Option<(&'a String, &'a String)> {
+    let store = self.store;
+    self.keys.next().map(|key| (key, store.get(key)))
+  }
+
+  fn size_hint(&self) -> (uint, Option<uint>) {
+    self.keys.size_hint()
+  }
+}
+
+/// The stored settings at some point in time, as returned by
+/// `Settings::snapshot`.
+#[deriving(Clone)]
+pub struct Snapshot {
+  store: HashMap<String,String>,
+  order: Vec<String>,
+  sources: HashMap<String,Source>,
+  layers: HashMap<String,Layer>,
+}
+
+/// A read-only `Settings` struct, as returned by `Settings::freeze`.
+#[deriving(Clone)]
+pub struct FrozenSettings {
+  settings: Settings,
+}
+
+impl FrozenSettings {
+  /// Fetch a setting for a key. Fails if the setting is present but could not be
+  /// parsed.
+  pub fn fetch<A: Key, T: FromStr>(&self, setting: A) -> Option<T> {
+    self.settings.fetch(setting)
+  }
+
+  /// Fetch the raw string stored for a key, without parsing it.
+  pub fn get_raw<A: Key>(&self, setting: A) -> Option<String> {
+    self.settings.get_raw(setting)
+  }
+
+  /// Check whether a setting is present for a key. The value is not parsed.
+  pub fn contains<A: Key>(&self, setting: A) -> bool {
+    self.settings.contains(setting)
+  }
+
+  /// An iterator over all stored settings as pairs of keys and raw values, in
+  /// the order they were first set.
+  pub fn iter<'a>(&'a self) -> Entries<'a> {
+    self.settings.iter()
+  }
+
+  /// Turn the frozen settings back into a mutable `Settings` struct.
+  pub fn thaw(self) -> Settings {
+    self.settings
+  }
+}
+
+/// A view on a `Settings` struct that prefixes every key with a namespace,
+/// separated by a dot. All settings are read from and written to the
+/// underlying `Settings`.
+pub struct ScopedSettings<'a> {
+  settings: &'a mut Settings,
+  prefix: String,
+}
+
+impl<'a> ScopedSettings<'a> {
+  fn key<A: Key>(&self, setting: A) -> String {
+    format!("{}.{}", self.prefix, setting.as_key())
+  }
+
+  /// Set a settings key within the namespace to a value.
+  pub fn set<A: Key, T: ToStr>(&mut self, setting: A, value: T) {
+    let key = self.key(setting);
+    self.settings.set(key, value);
+  }
+
+  /// Remove the setting for a key within the namespace.
+  pub fn unset<A: Key>(&mut self, setting: A) -> Option<String> {
+    let key = self.key(setting);
+    self.settings.unset(key)
+  }
+
+  /// Check whether a setting is present for a key within the namespace.
+  pub fn contains<A: Key>(&self, setting: A) -> bool {
+    self.settings.contains(self.key(setting))
+  }
+
+  /// Fetch the raw string stored for a key within the namespace.
+  pub fn get_raw<A: Key>(&self, setting: A) -> Option<String> {
+    self.settings.get_raw(self.key(setting))
+  }
+
+  /// Fetch a setting for a key within the namespace. Fails if the setting is
+  /// present but could not be parsed.
+  pub fn fetch<A: Key, T: FromStr>(&self, setting: A) -> Option<T> {
+    self.settings.fetch(self.key(setting))
+  }
+
+  /// Fetch a setting for a key within the namespace without failing.
+  pub fn try_fetch<A: Key, T: FromStr>(&self, setting: A) -> Result<Option<T>, ParseError> {
+    self.settings.try_fetch(self.key(setting))
+  }
+
+  /// Create a view on a nested namespace.
+  pub fn scope<'b>(&'b mut self, prefix: &str) -> ScopedSettings<'b> {
+    let prefix = self.key(prefix);
+    ScopedSettings { settings: &mut *self.settings, prefix: prefix }
+  }
+}
+
+/// A single, possibly absent setting in a `Settings` struct, as returned by
+/// `Settings::entry`.
+pub struct Entry<'a> {
+  settings: &'a mut Settings,
+  key: String,
+}
+
+impl<'a> Entry<'a> {
+  /// Set the setting to a value if it is not present.
+  pub fn or_set<T: ToStr>(self, value: T) -> Entry<'a> {
+    self.settings.set_default(self.key.as_slice(), value);
+    self
+  }
+
+  /// Replace the raw value of the setting with the result of the given function,
+  /// if it is present.
+  pub fn and_modify(self, f: |&str| -> String) -> Entry<'a> {
+    match self.settings.get_raw(self.key.as_slice()) {
+      Some(value) => { self.settings.set(self.key.as_slice(), f(value.as_slice())) },
+      None => {}
+    }
+    self
+  }
+}
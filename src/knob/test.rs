@@ -1,11 +1,28 @@
 extern crate knob;
 extern crate getopts;
+extern crate serialize;
 
 #[cfg(test)]
 mod tests {
-  use knob::Settings;
+  use knob::{Settings, Key, ParseError, OptionInfo, OptSpec, TypeCheck, parses, parse_bool};
+  use knob::{FrozenSettings, FetchCache};
+  use knob::{FlagKind, ValueKind, MultiKind};
+  use knob::{DefaultLayer, FileLayer, CliLayer};
+  use knob::{ManualSource, FileSource, EnvSource, CommandLineSource};
+  use knob::{Added, Removed, Modified};
+  use knob::{ArgumentMissing, UnrecognizedOption, OptionMissing, OptionDuplicated,
+             UnexpectedArgument, UnsupportedValue, InvalidValue, InvalidToml, InvalidYaml,
+             MissingReference, CyclicReference, Missing, Parse, UnknownKey};
   use std::io::net::ip::{SocketAddr, IpAddr, Ipv4Addr};
-  use getopts::{optopt,reqopt,usage};
+  use std::io::{File, MemReader, TempDir};
+  use std::os;
+  use std::time::Duration;
+  use std::default::Default;
+  use std::fmt;
+  use std::from_str::FromStr;
+  use std::num::from_str_radix;
+  use getopts::{optopt,reqopt,optflag,optflagopt,optmulti,usage};
+  use serialize::json;
 
   #[deriving(Show)]
   enum Keys {
@@ -43,6 +60,13 @@ mod tests {
     }
   }
 
+  fn valid_port(value: &str) -> Result<(), String> {
+    match from_str::<int>(value) {
+      Some(port) if port >= 1 && port <= 65535 => { Ok(()) },
+      _ => { Err(format!("{} is not a port", value)) }
+    }
+  }
+
   #[test]
   fn test_simple_conversion() {
     let mut settings = Settings::new();
@@ -60,6 +84,51 @@ mod tests {
     assert_eq!(Some(12345), settings)
   }
 
+  #[test]
+  fn test_try_fetch_parseable() {
+    let mut settings = Settings::new();
+    settings.set("port", 12345);
+    let port: Result<Option<int>, ParseError> = settings.try_fetch("port");
+    assert_eq!(Ok(Some(12345)), port)
+  }
+
+  #[test]
+  fn test_try_fetch_missing() {
+    let settings = Settings::new();
+    let port: Result<Option<int>, ParseError> = settings.try_fetch("port");
+    assert_eq!(Ok(None), port)
+  }
+
+  #[test]
+  fn test_try_fetch_garbage() {
+    let mut settings = Settings::new();
+    settings.set("port", "foobar");
+    let port: Result<Option<int>, ParseError> = settings.try_fetch("port");
+    let error = port.unwrap_err();
+    assert_eq!(error.key, "port".to_string());
+    assert_eq!(error.value, "foobar".to_string())
+  }
+
+  #[test]
+  fn test_fetch_or_missing() {
+    let settings = Settings::new();
+    assert_eq!(settings.fetch_or("missing", 8080), 8080)
+  }
+
+  #[test]
+  fn test_fetch_or_present() {
+    let mut settings = Settings::new();
+    settings.set("port", 12345);
+    assert_eq!(settings.fetch_or("port", 80), 12345)
+  }
+
+  #[test]
+  fn test_fetch_or_garbage() {
+    let mut settings = Settings::new();
+    settings.set("port", "foobar");
+    assert_eq!(settings.fetch_or("port", 80), 80)
+  }
+
   #[test]
   fn test_enum() {
     let mut settings = Settings::new();
@@ -103,7 +172,7 @@ mod tests {
     let args = vec!("myprog".to_string(), "-p".to_string(), "3000".to_string());
     let error = settings.load_args(args);
 
-    assert!(error.is_none());
+    assert!(error.is_ok());
     assert_eq!(settings.fetch("port"), Some(3000))
   }
 
@@ -115,7 +184,7 @@ mod tests {
     let args = vec!("myprog".to_string(), "-p".to_string(), "3000".to_string());
     let error = settings.load_args(args);
 
-    assert!(error.is_none());
+    assert!(error.is_ok());
     assert_eq!(settings.fetch("port"), Some(3000))
   }
 
@@ -127,7 +196,7 @@ mod tests {
     let args = vec!("myprog".to_string());
     let error = settings.load_args(args);
 
-    assert!(error.is_some());
+    assert!(error.is_err());
     let port: Option<int> = settings.fetch("port");
     assert_eq!(port, None)
   }
@@ -142,6 +211,2411 @@ mod tests {
     assert!(usage.as_slice().contains("--port"))
   }
 
+  #[test]
+  fn test_contains_present() {
+    let mut settings = Settings::new();
+    settings.set("port", 12345);
+    assert!(settings.contains("port"))
+  }
+
+  #[test]
+  fn test_contains_absent() {
+    let settings = Settings::new();
+    assert!(!settings.contains("port"))
+  }
+
+  #[test]
+  fn test_contains_enum() {
+    let mut settings = Settings::new();
+    settings.set(Port, 12345);
+    assert!(settings.contains(Port));
+    assert!(settings.contains("Port"));
+    assert!(!settings.contains(Ip))
+  }
+
+  #[test]
+  fn test_unset() {
+    let mut settings = Settings::new();
+    settings.set("port", 12345);
+    let previous = settings.unset("port");
+    assert_eq!(previous, Some("12345".to_string()));
+    assert!(!settings.contains("port"))
+  }
+
+  #[test]
+  fn test_unset_absent() {
+    let mut settings = Settings::new();
+    assert_eq!(settings.unset("port"), None)
+  }
+
+  #[test]
+  fn test_get_raw() {
+    let mut settings = Settings::new();
+    settings.set("path", "  /tmp/my files ");
+    assert_eq!(settings.get_raw("path"), Some("  /tmp/my files ".to_string()));
+    assert_eq!(settings.get_raw("missing"), None)
+  }
+
+  #[test]
+  fn test_load_file() {
+    let mut settings = Settings::new();
+    let result = settings.load_file(&Path::new("src/knob/fixtures/settings.conf"));
+
+    assert!(result.is_ok());
+    assert_eq!(settings.fetch("port"), Some(12345));
+    assert_eq!(settings.get_raw("ip"), Some("127.0.0.1".to_string()));
+    assert_eq!(settings.get_raw("query"), Some("a=b&c=d".to_string()));
+    assert!(!settings.contains("# The port to bind to"))
+  }
+
+  #[test]
+  fn test_load_file_missing() {
+    let mut settings = Settings::new();
+    let result = settings.load_file(&Path::new("src/knob/fixtures/missing.conf"));
+    assert!(result.is_err())
+  }
+
+  #[test]
+  fn test_save_file_round_trip() {
+    let dir = TempDir::new("knob").unwrap();
+    let path = dir.path().join("settings.conf");
+
+    let mut settings = Settings::new();
+    settings.set("port", 12345);
+    settings.set("ip", "127.0.0.1");
+    settings.set("environment", "production");
+    assert!(settings.save_file(&path).is_ok());
+
+    let mut loaded = Settings::new();
+    assert!(loaded.load_file(&path).is_ok());
+    assert_eq!(loaded.fetch("port"), Some(12345));
+    assert_eq!(loaded.get_raw("ip"), Some("127.0.0.1".to_string()));
+    assert_eq!(loaded.get_raw("environment"), Some("production".to_string()))
+  }
+
+  #[test]
+  fn test_save_file_sorted() {
+    let dir = TempDir::new("knob").unwrap();
+    let path = dir.path().join("settings.conf");
+
+    let mut settings = Settings::new();
+    assert!(settings.load_args(vec!("myprog".to_string())).is_ok());
+    settings.set("port", 12345);
+    settings.set("environment", "production");
+    settings.set("ip", "127.0.0.1");
+    assert!(settings.save_file(&path).is_ok());
+
+    let contents = File::open(&path).read_to_str().unwrap();
+    assert_eq!(contents, "environment=production\nip=127.0.0.1\nport=12345\n".to_string())
+  }
+
+  #[test]
+  fn test_load_env() {
+    os::setenv("KNOBENV_PORT", "12345");
+    os::setenv("KNOBENV_LOG_LEVEL", "warn");
+    os::setenv("KNOBENVX_PORT", "4000");
+
+    let mut settings = Settings::new();
+    assert!(settings.load_env("KNOBENV").is_ok());
+
+    assert_eq!(settings.fetch("port"), Some(12345));
+    assert_eq!(settings.get_raw("log_level"), Some("warn".to_string()));
+    assert!(!settings.contains("KNOBENV_PORT"))
+  }
+
+  #[test]
+  fn test_load_env_empty_prefix() {
+    os::setenv("KNOBENV_ALL", "everything");
+
+    let mut settings = Settings::new();
+    assert!(settings.load_env("").is_ok());
+
+    assert_eq!(settings.get_raw("knobenv_all"), Some("everything".to_string()))
+  }
+
+  #[test]
+  fn test_set_layered_keeps_higher_layer() {
+    let mut settings = Settings::new();
+    settings.set_layered("port", 3000, CliLayer);
+    settings.set_layered("port", 8080, DefaultLayer);
+    assert_eq!(settings.fetch("port"), Some(3000))
+  }
+
+  #[test]
+  fn test_set_layered_overrides_lower_layer() {
+    let mut settings = Settings::new();
+    settings.set_layered("port", 4000, FileLayer);
+    settings.set_layered("port", 3000, CliLayer);
+    assert_eq!(settings.fetch("port"), Some(3000))
+  }
+
+  #[test]
+  fn test_set_overrides_layers() {
+    let mut settings = Settings::new();
+    settings.set_layered("port", 3000, CliLayer);
+    settings.set("port", 4000);
+    settings.set_layered("port", 8080, DefaultLayer);
+    assert_eq!(settings.fetch("port"), Some(8080))
+  }
+
+  #[test]
+  fn test_load_args_wins_over_file() {
+    let mut settings = Settings::new();
+    settings.opt(optopt("p", "port", "The port to bind to", "eg: 4000"));
+    let args = vec!("myprog".to_string(), "-p".to_string(), "3000".to_string());
+    assert!(settings.load_args(args).is_ok());
+    settings.load_file(&Path::new("src/knob/fixtures/settings.conf")).unwrap();
+    assert_eq!(settings.fetch("port"), Some(3000))
+  }
+
+  #[test]
+  fn test_iter() {
+    let mut settings = Settings::new();
+    settings.set("port", 12345);
+    settings.set("ip", "127.0.0.1");
+    settings.set("environment", "production");
+
+    assert_eq!(settings.iter().count(), 3);
+    for (key, value) in settings.iter() {
+      assert_eq!(settings.get_raw(key.as_slice()), Some(value.clone()))
+    }
+  }
+
+  #[test]
+  fn test_keys() {
+    let mut settings = Settings::new();
+    settings.set("port", 12345);
+    settings.set("ip", "127.0.0.1");
+
+    let keys: Vec<&String> = settings.keys().collect();
+    assert_eq!(keys, vec!(&"port".to_string(), &"ip".to_string()))
+  }
+
+  #[test]
+  fn test_clone() {
+    let mut settings = Settings::new();
+    settings.opt(optopt("p", "port", "The port to bind to", "eg: 4000"));
+    settings.set("port", 12345);
+
+    let mut cloned = settings.clone();
+    cloned.set("port", 4000);
+    cloned.set("ip", "127.0.0.1");
+
+    assert_eq!(settings.fetch("port"), Some(12345));
+    assert!(!settings.contains("ip"));
+    assert_eq!(cloned.fetch("port"), Some(4000));
+    assert!(cloned.usage("".to_string()).as_slice().contains("--port"))
+  }
+
+  #[test]
+  fn test_opt_flag_given() {
+    let mut settings = Settings::new();
+    settings.opt(optflag("v", "verbose", "Print more output"));
+
+    let args = vec!("myprog".to_string(), "-v".to_string());
+    let error = settings.load_args(args);
+
+    assert!(error.is_ok());
+    assert_eq!(settings.fetch("verbose"), Some(true))
+  }
+
+  #[test]
+  fn test_opt_flag_omitted() {
+    let mut settings = Settings::new();
+    settings.opt(optflag("v", "verbose", "Print more output"));
+
+    let args = vec!("myprog".to_string());
+    let error = settings.load_args(args);
+
+    assert!(error.is_ok());
+    let verbose: Option<bool> = settings.fetch("verbose");
+    assert_eq!(verbose, None)
+  }
+
+  #[test]
+  fn test_opt_multi_zero() {
+    let mut settings = Settings::new();
+    settings.opt(optmulti("I", "include", "Add a path to search", "PATH"));
+
+    let args = vec!("myprog".to_string());
+    let error = settings.load_args(args);
+
+    assert!(error.is_ok());
+    let includes: Option<Vec<String>> = settings.fetch_list("include");
+    assert_eq!(includes, None)
+  }
+
+  #[test]
+  fn test_opt_multi_one() {
+    let mut settings = Settings::new();
+    settings.opt(optmulti("I", "include", "Add a path to search", "PATH"));
+
+    let args = vec!("myprog".to_string(), "-I".to_string(), "src".to_string());
+    let error = settings.load_args(args);
+
+    assert!(error.is_ok());
+    let includes: Option<Vec<String>> = settings.fetch_list("include");
+    assert_eq!(includes, Some(vec!("src".to_string())))
+  }
+
+  #[test]
+  fn test_opt_multi_three() {
+    let mut settings = Settings::new();
+    settings.opt(optmulti("p", "port", "A port to bind to", "PORT"));
+
+    let args = vec!("myprog".to_string(),
+                    "-p".to_string(), "3000".to_string(),
+                    "--port".to_string(), "3001".to_string(),
+                    "-p".to_string(), "3002".to_string());
+    let error = settings.load_args(args);
+
+    assert!(error.is_ok());
+    let ports: Option<Vec<int>> = settings.fetch_list("port");
+    assert_eq!(ports, Some(vec!(3000, 3001, 3002)))
+  }
+
+  #[test]
+  fn test_set_list_round_trip() {
+    let mut settings = Settings::new();
+    settings.set_list("ports", [1i, 2, 3]);
+    assert_eq!(settings.fetch_list("ports"), Some(vec!(1i, 2, 3)))
+  }
+
+  #[test]
+  fn test_set_list_empty() {
+    let mut settings = Settings::new();
+    let empty: &[int] = [];
+    settings.set_list("ports", empty);
+    assert_eq!(settings.fetch_list("ports"), Some(empty.to_vec()))
+  }
+
+  #[test]
+  fn test_fetch_list_missing() {
+    let settings = Settings::new();
+    let ports: Option<Vec<int>> = settings.fetch_list("ports");
+    assert_eq!(ports, None)
+  }
+
+  #[test]
+  #[should_fail]
+  fn test_set_list_rejects_separator() {
+    let mut settings = Settings::new();
+    settings.set_list("hosts", ["localhost", "evil\x1fhost"]);
+  }
+
+  #[test]
+  fn test_merge() {
+    let mut base = Settings::new();
+    base.opt(optopt("p", "port", "The port to bind to", "eg: 4000"));
+    base.set("port", 4000);
+    base.set("ip", "127.0.0.1");
+
+    let mut overlay = Settings::new();
+    overlay.opt(optopt("p", "port", "The port to bind to", "eg: 4000"));
+    overlay.opt(optopt("e", "environment", "The environment to run in", ""));
+    overlay.set("port", 12345);
+
+    base.merge(&overlay);
+
+    assert_eq!(base.fetch("port"), Some(12345));
+    assert_eq!(base.get_raw("ip"), Some("127.0.0.1".to_string()));
+
+    let args = vec!("myprog".to_string(), "-e".to_string(), "test".to_string());
+    assert!(base.load_args(args).is_ok());
+    assert_eq!(base.get_raw("environment"), Some("test".to_string()))
+  }
+
+  #[test]
+  fn test_fetch_or_set_missing() {
+    let mut settings = Settings::new();
+    assert_eq!(settings.fetch_or_set("port", 8080), 8080);
+    assert_eq!(settings.get_raw("port"), Some("8080".to_string()))
+  }
+
+  #[test]
+  fn test_fetch_or_set_present() {
+    let mut settings = Settings::new();
+    settings.set("port", 12345);
+    assert_eq!(settings.fetch_or_set("port", 8080), 12345);
+    assert_eq!(settings.get_raw("port"), Some("12345".to_string()))
+  }
+
+  #[test]
+  fn test_load_args_argument_missing() {
+    let mut settings = Settings::new();
+    settings.opt(optopt("p", "port", "The port to bind to", "eg: 4000"));
+
+    let args = vec!("myprog".to_string(), "-p".to_string());
+    assert_eq!(settings.load_args(args), Err(ArgumentMissing("p".to_string())))
+  }
+
+  #[test]
+  fn test_load_args_unrecognized_option() {
+    let mut settings = Settings::new();
+    settings.opt(optopt("p", "port", "The port to bind to", "eg: 4000"));
+
+    let args = vec!("myprog".to_string(), "--prot".to_string(), "3000".to_string());
+    assert_eq!(settings.load_args(args), Err(UnrecognizedOption("prot".to_string())))
+  }
+
+  #[test]
+  fn test_load_args_option_missing() {
+    let mut settings = Settings::new();
+    settings.opt(reqopt("p", "port", "The port to bind to", "eg: 4000"));
+
+    let args = vec!("myprog".to_string());
+    assert_eq!(settings.load_args(args), Err(OptionMissing("port".to_string())))
+  }
+
+  #[test]
+  fn test_load_args_option_duplicated() {
+    let mut settings = Settings::new();
+    settings.opt(optopt("p", "port", "The port to bind to", "eg: 4000"));
+
+    let args = vec!("myprog".to_string(),
+                    "-p".to_string(), "3000".to_string(),
+                    "-p".to_string(), "3001".to_string());
+    assert_eq!(settings.load_args(args), Err(OptionDuplicated("port".to_string())))
+  }
+
+  #[test]
+  fn test_load_args_unexpected_argument() {
+    let mut settings = Settings::new();
+    settings.opt(optflag("v", "verbose", "Print more output"));
+
+    let args = vec!("myprog".to_string(), "--verbose=yes".to_string());
+    assert_eq!(settings.load_args(args), Err(UnexpectedArgument("verbose".to_string())))
+  }
+
+  #[test]
+  fn test_error_message() {
+    let error = OptionMissing("port".to_string());
+    assert_eq!(error.to_str(), "required option 'port' missing".to_string())
+  }
+
+  #[test]
+  fn test_set_default_absent() {
+    let mut settings = Settings::new();
+    settings.set_default("port", 8080);
+    assert_eq!(settings.fetch("port"), Some(8080))
+  }
+
+  #[test]
+  fn test_set_default_present() {
+    let mut settings = Settings::new();
+    settings.set("port", 12345);
+    settings.set_default("port", 8080);
+    assert_eq!(settings.fetch("port"), Some(12345))
+  }
+
+  #[test]
+  fn test_set_default_present_empty() {
+    let mut settings = Settings::new();
+    settings.set("environment", "");
+    settings.set_default("environment", "production");
+    assert_eq!(settings.get_raw("environment"), Some("".to_string()))
+  }
+
+  #[test]
+  fn test_load_args_all_reports_every_missing_option() {
+    let mut settings = Settings::new();
+    settings.opt(reqopt("p", "port", "The port to bind to", "eg: 4000"));
+    settings.opt(reqopt("i", "ip", "The ip to bind to", "eg: 127.0.0.1"));
+    settings.opt(reqopt("e", "environment", "The environment to run in", ""));
+
+    let args = vec!("myprog".to_string(), "-p".to_string(), "3000".to_string());
+    let errors = settings.load_args_all(args).unwrap_err();
+
+    assert_eq!(errors, vec!(OptionMissing("ip".to_string()),
+                            OptionMissing("environment".to_string())));
+    let port: Option<int> = settings.fetch("port");
+    assert_eq!(port, None)
+  }
+
+  #[test]
+  fn test_load_args_all_given() {
+    let mut settings = Settings::new();
+    settings.opt(reqopt("p", "port", "The port to bind to", "eg: 4000"));
+    settings.opt(reqopt("i", "ip", "The ip to bind to", "eg: 127.0.0.1"));
+
+    let args = vec!("myprog".to_string(),
+                    "-p".to_string(), "3000".to_string(),
+                    "-i".to_string(), "127.0.0.1".to_string());
+    assert!(settings.load_args_all(args).is_ok());
+    assert_eq!(settings.fetch("port"), Some(3000))
+  }
+
+  #[test]
+  fn test_to_json() {
+    let mut settings = Settings::new();
+    assert!(settings.load_args(vec!("myprog".to_string())).is_ok());
+    settings.set("port", 12345);
+    settings.set("ip", "127.0.0.1");
+
+    let exported = settings.to_json(false);
+    assert_eq!(exported, "{\"ip\":\"127.0.0.1\",\"port\":\"12345\"}".to_string());
+
+    let parsed = json::from_str(exported.as_slice()).unwrap();
+    assert_eq!(parsed.find(&"port".to_string()).and_then(|port| port.as_string()), Some("12345"));
+    assert!(parsed.find(&"knob.progname".to_string()).is_none())
+  }
+
+  #[test]
+  fn test_to_json_internal() {
+    let mut settings = Settings::new();
+    assert!(settings.load_args(vec!("myprog".to_string())).is_ok());
+
+    let exported = settings.to_json(true);
+    assert!(exported.as_slice().contains("\"knob.progname\":\"myprog\""))
+  }
+
+  #[test]
+  fn test_load_json() {
+    let mut settings = Settings::new();
+    let result = settings.load_json("{\"ip\": \"127.0.0.1\", \"environment\": \"production\"}");
+
+    assert!(result.is_ok());
+    assert_eq!(settings.get_raw("ip"), Some("127.0.0.1".to_string()));
+    assert_eq!(settings.get_raw("environment"), Some("production".to_string()))
+  }
+
+  #[test]
+  fn test_load_json_scalars() {
+    let mut settings = Settings::new();
+    let result = settings.load_json("{\"port\": 12345, \"verbose\": true}");
+
+    assert!(result.is_ok());
+    assert_eq!(settings.fetch("port"), Some(12345));
+    assert_eq!(settings.fetch("verbose"), Some(true))
+  }
+
+  #[test]
+  fn test_load_json_nested() {
+    let mut settings = Settings::new();
+    let result = settings.load_json("{\"port\": 12345, \"db\": {\"host\": \"localhost\"}}");
+
+    assert_eq!(result, Err(UnsupportedValue("db".to_string())));
+    assert!(!settings.contains("port"))
+  }
+
+  #[test]
+  fn test_load_json_garbage() {
+    let mut settings = Settings::new();
+    assert!(settings.load_json("port=12345").is_err())
+  }
+
+  #[test]
+  fn test_case_insensitive() {
+    let mut settings = Settings::new();
+    settings.set_case_insensitive(true);
+    settings.set("PORT", 12345);
+    assert_eq!(settings.fetch("port"), Some(12345));
+    assert_eq!(settings.fetch("Port"), Some(12345))
+  }
+
+  #[test]
+  fn test_case_sensitive_by_default() {
+    let mut settings = Settings::new();
+    settings.set("PORT", 12345);
+    let port: Option<int> = settings.fetch("port");
+    assert_eq!(port, None)
+  }
+
+  #[test]
+  fn test_case_insensitive_rekeys_existing() {
+    let mut settings = Settings::new();
+    settings.set("PORT", 12345);
+    settings.set_case_insensitive(true);
+    assert_eq!(settings.fetch("port"), Some(12345));
+    assert_eq!(settings.fetch(Port), Some(12345))
+  }
+
+  #[test]
+  fn test_scope_fetch() {
+    let mut settings = Settings::new();
+    settings.set("db.port", 5432);
+    settings.set("port", 12345);
+    assert_eq!(settings.scope("db").fetch("port"), Some(5432))
+  }
+
+  #[test]
+  fn test_scope_set() {
+    let mut settings = Settings::new();
+    {
+      let mut db = settings.scope("db");
+      db.set("host", "localhost");
+      db.scope("replica").set("host", "replica.local");
+    }
+    assert_eq!(settings.get_raw("db.host"), Some("localhost".to_string()));
+    assert_eq!(settings.get_raw("db.replica.host"), Some("replica.local".to_string()));
+    assert!(!settings.contains("host"))
+  }
+
+  #[test]
+  fn test_len_empty() {
+    let settings = Settings::new();
+    assert_eq!(settings.len(), 0);
+    assert!(settings.is_empty())
+  }
+
+  #[test]
+  fn test_len() {
+    let mut settings = Settings::new();
+    settings.set("port", 12345);
+    settings.set("ip", "127.0.0.1");
+    settings.set("port", 4000);
+    assert_eq!(settings.len(), 2);
+    assert!(!settings.is_empty())
+  }
+
+  #[test]
+  fn test_len_counts_internal_keys() {
+    let mut settings = Settings::new();
+    assert!(settings.load_args(vec!("myprog".to_string())).is_ok());
+    assert_eq!(settings.len(), 2)
+  }
+
+  #[test]
+  fn test_clear() {
+    let mut settings = Settings::new();
+    settings.opt(optopt("p", "port", "The port to bind to", "eg: 4000"));
+
+    let args = vec!("myprog".to_string(), "-p".to_string(), "3000".to_string());
+    assert!(settings.load_args(args.clone()).is_ok());
+    settings.set("ip", "127.0.0.1");
+
+    settings.clear();
+    assert!(settings.is_empty());
+
+    assert!(settings.load_args(args).is_ok());
+    assert_eq!(settings.fetch("port"), Some(3000));
+    assert!(!settings.contains("ip"))
+  }
+
+  #[test]
+  fn test_default() {
+    let mut settings: Settings = Default::default();
+    assert!(settings.is_empty());
+    settings.set("port", 12345);
+    assert_eq!(settings.fetch("port"), Some(12345))
+  }
+
+  #[test]
+  fn test_fetch_map() {
+    let mut settings = Settings::new();
+    settings.set("workers", 4);
+
+    let parallel = settings.fetch_map("workers", |workers: Option<int>| {
+      workers.map_or(false, |workers| workers > 1)
+    });
+    assert!(parallel);
+
+    let parallel = settings.fetch_map("missing", |workers: Option<int>| {
+      workers.map_or(false, |workers| workers > 1)
+    });
+    assert!(!parallel)
+  }
+
+  #[test]
+  fn test_load_args_twice() {
+    let mut settings = Settings::new();
+    settings.opt(optopt("p", "port", "The port to bind to", "eg: 4000"));
+    settings.opt(optopt("e", "environment", "The environment to run in", ""));
+
+    let args = vec!("myprog".to_string(), "-p".to_string(), "3000".to_string());
+    assert!(settings.load_args(args).is_ok());
+    let args = vec!("myprog".to_string(), "-e".to_string(), "test".to_string());
+    assert!(settings.load_args(args).is_ok());
+
+    assert_eq!(settings.fetch("port"), Some(3000));
+    assert_eq!(settings.get_raw("environment"), Some("test".to_string()));
+    assert_eq!(settings.len(), 4)
+  }
+
+  #[test]
+  fn test_fetch_parse() {
+    let mut settings = Settings::new();
+    settings.set("mask", "0xff");
+    let mask: Option<int> = settings.fetch_parse("mask", |string| {
+      if string.starts_with("0x") { from_str_radix(string.slice_from(2), 16) } else { None }
+    });
+    assert_eq!(mask, Some(255))
+  }
+
+  #[test]
+  fn test_usage_brief() {
+    let mut settings = Settings::new();
+    settings.opt(reqopt("p", "port", "The port to bind to", "eg: 4000"));
+    let usage = settings.usage_brief();
+
+    assert!(usage.as_slice().contains("--port"));
+    assert!(!usage.as_slice().contains("this is how it works"))
+  }
+
+  #[test]
+  fn test_progname() {
+    let mut settings = Settings::new();
+    assert_eq!(settings.progname(), None);
+    assert!(settings.load_args(vec!("myprog".to_string())).is_ok());
+    assert_eq!(settings.progname(), Some("myprog".to_string()))
+  }
+
+  #[test]
+  fn test_free_args() {
+    let mut settings = Settings::new();
+    settings.opt(optopt("p", "port", "The port to bind to", "eg: 4000"));
+
+    let args = vec!("prog".to_string(), "-p".to_string(), "3000".to_string(), "file.txt".to_string());
+    assert!(settings.load_args(args).is_ok());
+    assert_eq!(settings.free_args(), vec!("file.txt".to_string()))
+  }
+
+  #[test]
+  fn test_free_args_empty() {
+    let mut settings = Settings::new();
+    settings.opt(optopt("p", "port", "The port to bind to", "eg: 4000"));
+    assert_eq!(settings.free_args(), vec!());
+
+    let args = vec!("prog".to_string(), "-p".to_string(), "3000".to_string());
+    assert!(settings.load_args(args).is_ok());
+    assert_eq!(settings.free_args(), vec!())
+  }
+
+  #[test]
+  fn test_opt_flagopt_bare() {
+    let mut settings = Settings::new();
+    settings.opt(optflagopt("c", "color", "Colorize the output", "WHEN"));
+
+    let args = vec!("myprog".to_string(), "--color".to_string());
+    assert!(settings.load_args(args).is_ok());
+    assert_eq!(settings.get_raw("color"), Some("true".to_string()))
+  }
+
+  #[test]
+  fn test_opt_flagopt_valued() {
+    let mut settings = Settings::new();
+    settings.opt(optflagopt("c", "color", "Colorize the output", "WHEN"));
+
+    let args = vec!("myprog".to_string(), "--color=always".to_string());
+    assert!(settings.load_args(args).is_ok());
+    assert_eq!(settings.get_raw("color"), Some("always".to_string()))
+  }
+
+  #[test]
+  fn test_opt_flagopt_omitted() {
+    let mut settings = Settings::new();
+    settings.opt(optflagopt("c", "color", "Colorize the output", "WHEN"));
+
+    assert!(settings.load_args(vec!("myprog".to_string())).is_ok());
+    assert!(!settings.contains("color"))
+  }
+
+  #[test]
+  fn test_entry_vacant() {
+    let mut settings = Settings::new();
+    settings.entry("port").and_modify(|port| port.to_string() + "0").or_set("8080");
+    assert_eq!(settings.fetch("port"), Some(8080))
+  }
+
+  #[test]
+  fn test_entry_occupied() {
+    let mut settings = Settings::new();
+    settings.set("port", 1234);
+    settings.entry("port").or_set("8080");
+    assert_eq!(settings.fetch("port"), Some(1234));
+
+    settings.entry("port").and_modify(|port| port.to_string() + "5").or_set("8080");
+    assert_eq!(settings.fetch("port"), Some(12345))
+  }
+
+  #[test]
+  fn test_expand_env() {
+    os::setenv("KNOBEXPAND_HOME", "/home/knob");
+
+    let mut settings = Settings::new();
+    settings.set("data", "${KNOBEXPAND_HOME}/data");
+    settings.expand_env(true);
+
+    assert_eq!(settings.get_raw("data"), Some("/home/knob/data".to_string()))
+  }
+
+  #[test]
+  fn test_expand_env_unknown() {
+    os::unsetenv("KNOBEXPAND_UNKNOWN");
+
+    let mut settings = Settings::new();
+    settings.set("data", "${KNOBEXPAND_UNKNOWN}/data");
+    settings.set("cache", "${KNOBEXPAND_UNKNOWN}/cache");
+    settings.expand_env(true);
+    assert_eq!(settings.get_raw("data"), Some("${KNOBEXPAND_UNKNOWN}/data".to_string()));
+
+    settings.expand_env(false);
+    assert_eq!(settings.get_raw("cache"), Some("/cache".to_string()))
+  }
+
+  #[test]
+  fn test_expand_env_escape() {
+    os::setenv("KNOBEXPAND_PRICE", "12");
+
+    let mut settings = Settings::new();
+    settings.set("price", "$$${KNOBEXPAND_PRICE} or $$$${KNOBEXPAND_PRICE}");
+    settings.expand_env(true);
+
+    assert_eq!(settings.get_raw("price"), Some("$12 or $${KNOBEXPAND_PRICE}".to_string()))
+  }
+
+  #[test]
+  fn test_freeze() {
+    let mut settings = Settings::new();
+    settings.set("port", 12345);
+    settings.set("ip", "127.0.0.1");
+
+    let frozen = settings.freeze();
+    assert_eq!(frozen.fetch("port"), Some(12345));
+    assert_eq!(frozen.get_raw("ip"), Some("127.0.0.1".to_string()));
+    assert!(frozen.contains("ip"));
+    assert_eq!(frozen.iter().count(), 2)
+  }
+
+  #[test]
+  fn test_thaw() {
+    let mut settings = Settings::new();
+    settings.set("port", 12345);
+
+    let mut thawed = settings.freeze().thaw();
+    thawed.set("port", 4000);
+    assert_eq!(thawed.fetch("port"), Some(4000))
+  }
+
+  #[test]
+  fn test_extend() {
+    let mut settings = Settings::new();
+    let pairs = vec!(("port", "4000"), ("ip", "127.0.0.1"), ("port", "12345"));
+    settings.extend(pairs.move_iter());
+
+    assert_eq!(settings.len(), 2);
+    assert_eq!(settings.fetch("port"), Some(12345));
+    assert_eq!(settings.get_raw("ip"), Some("127.0.0.1".to_string()))
+  }
+
+  #[test]
+  fn test_usage_wrapped() {
+    let mut settings = Settings::new();
+    settings.opt(optopt("p", "port", "The port to bind to. This is a very long description that \
+                                      would overflow a narrow terminal if it were not wrapped at \
+                                      a sensible column.", "PORT"));
+    settings.opt(optflag("", "a-very-long-option-name", "Needs a line of its own"));
+    let usage = settings.usage_wrapped("Usage: myprog [options]", 50);
+
+    assert!(usage.as_slice().contains("Usage: myprog [options]"));
+    assert!(usage.as_slice().contains("--port"));
+    assert!(usage.as_slice().contains("sensible column."));
+    for line in usage.as_slice().lines() {
+      assert!(line.len() <= 50, "line too long: {}", line)
+    }
+  }
+
+  #[test]
+  fn test_suggest_option() {
+    let mut settings = Settings::new();
+    settings.opt(optopt("p", "port", "The port to bind to", "eg: 4000"));
+    settings.opt(optopt("e", "environment", "The environment to run in", ""));
+
+    let args = vec!("myprog".to_string(), "--prot".to_string(), "3000".to_string());
+    match settings.load_args(args) {
+      Err(UnrecognizedOption(name)) => {
+        assert_eq!(settings.suggest_option(name.as_slice()), Some("port".to_string()))
+      },
+      _ => { fail!("expected an unrecognized option") }
+    }
+  }
+
+  #[test]
+  fn test_suggest_option_none() {
+    let mut settings = Settings::new();
+    settings.opt(optopt("p", "port", "The port to bind to", "eg: 4000"));
+    assert_eq!(settings.suggest_option("--verbose"), None)
+  }
+
+  #[test]
+  fn test_load_reader() {
+    let mut reader = MemReader::new("port=12345\nip = 127.0.0.1\n".as_bytes().to_vec());
+    let mut settings = Settings::new();
+
+    assert!(settings.load_reader(&mut reader).is_ok());
+    assert_eq!(settings.fetch("port"), Some(12345));
+    assert_eq!(settings.get_raw("ip"), Some("127.0.0.1".to_string()))
+  }
+
+  #[test]
+  fn test_load_reader_invalid_line() {
+    let mut reader = MemReader::new("port=12345\njust some words\n".as_bytes().to_vec());
+    let mut settings = Settings::new();
+    assert!(settings.load_reader(&mut reader).is_err())
+  }
+
+  #[test]
+  fn test_iter_insertion_order() {
+    let mut settings = Settings::new();
+    settings.set("c", 1);
+    settings.set("a", 2);
+    settings.set("b", 3);
+    settings.set("c", 4);
+
+    let pairs: Vec<(String, String)> = settings.iter().map(|(key, value)| {
+      (key.clone(), value.clone())
+    }).collect();
+    assert_eq!(pairs, vec!(("c".to_string(), "4".to_string()),
+                           ("a".to_string(), "2".to_string()),
+                           ("b".to_string(), "3".to_string())))
+  }
+
+  #[test]
+  fn test_iter_order_after_unset() {
+    let mut settings = Settings::new();
+    settings.set("c", 1);
+    settings.set("a", 2);
+    settings.unset("c");
+    settings.set("c", 3);
+
+    let keys: Vec<&String> = settings.keys().collect();
+    assert_eq!(keys, vec!(&"a".to_string(), &"c".to_string()))
+  }
+
+  #[test]
+  fn test_fetch_result() {
+    let mut settings = Settings::new();
+    settings.set("port", 12345);
+    settings.set("ip", "foobar");
+
+    let present: Result<Option<int>, String> = settings.fetch_result("port");
+    let absent: Result<Option<int>, String> = settings.fetch_result("missing");
+    let invalid: Result<Option<int>, String> = settings.fetch_result("ip");
+
+    assert_eq!(present, Ok(Some(12345)));
+    assert_eq!(absent, Ok(None));
+    assert_eq!(invalid, Err("foobar".to_string()))
+  }
+
+  #[test]
+  fn test_opt_alias() {
+    let mut settings = Settings::new();
+    settings.opt_alias(optopt("l", "listen", "The address to listen on", "ADDR"), "bind");
+    settings.opt_alias(optopt("b", "bind", "The address to bind to", "ADDR"), "bind");
+
+    let args = vec!("myprog".to_string(), "--listen".to_string(), "0.0.0.0:80".to_string());
+    assert!(settings.load_args(args).is_ok());
+    assert_eq!(settings.get_raw("bind"), Some("0.0.0.0:80".to_string()));
+    assert!(!settings.contains("listen"));
+
+    let args = vec!("myprog".to_string(), "-b".to_string(), "127.0.0.1:80".to_string());
+    assert!(settings.load_args(args).is_ok());
+    assert_eq!(settings.get_raw("bind"), Some("127.0.0.1:80".to_string()))
+  }
+
+  #[test]
+  fn test_opt_alias_last_given_wins() {
+    let mut settings = Settings::new();
+    settings.opt_alias(optopt("l", "listen", "The address to listen on", "ADDR"), "bind");
+    settings.opt_alias(optopt("b", "bind", "The address to bind to", "ADDR"), "bind");
+
+    let args = vec!("myprog".to_string(),
+                    "-b".to_string(), "127.0.0.1:80".to_string(),
+                    "-l".to_string(), "0.0.0.0:80".to_string());
+    assert!(settings.load_args(args).is_ok());
+    assert_eq!(settings.get_raw("bind"), Some("0.0.0.0:80".to_string()));
+
+    let args = vec!("myprog".to_string(),
+                    "--listen=0.0.0.0:80".to_string(),
+                    "--bind".to_string(), "127.0.0.1:80".to_string());
+    assert!(settings.load_args(args).is_ok());
+    assert_eq!(settings.get_raw("bind"), Some("127.0.0.1:80".to_string()))
+  }
+
+  #[test]
+  fn test_opt_alias_option_value() {
+    let mut settings = Settings::new();
+    settings.opt_alias(optopt("l", "listen", "The address to listen on", "ADDR"), "bind");
+    settings.opt_alias(optopt("b", "bind", "The address to bind to", "ADDR"), "bind");
+    settings.opt(optopt("o", "output", "The file to write to", "FILE"));
+
+    let args = vec!("myprog".to_string(), "--listen".to_string(), "0.0.0.0:80".to_string(),
+                    "-o/tmp/bind.log".to_string());
+    assert!(settings.load_args(args).is_ok());
+    assert_eq!(settings.get_raw("bind"), Some("0.0.0.0:80".to_string()))
+  }
+
+  #[test]
+  fn test_fetch_cached() {
+    let mut settings = Settings::new();
+    let mut cache = FetchCache::new();
+    settings.set("port", 12345);
+    assert_eq!(settings.fetch_cached(&mut cache, "port"), Some(12345i));
+    assert_eq!(settings.fetch_cached(&mut cache, "port"), Some(12345i));
+
+    settings.set("port", 4000);
+    assert_eq!(settings.fetch_cached(&mut cache, "port"), Some(4000i))
+  }
+
+  #[test]
+  fn test_fetch_cached_per_type() {
+    let mut settings = Settings::new();
+    let mut cache = FetchCache::new();
+    settings.set("port", 12345);
+    assert_eq!(settings.fetch_cached(&mut cache, "port"), Some(12345i));
+    assert_eq!(settings.fetch_cached(&mut cache, "port"), Some("12345".to_string()))
+  }
+
+  #[test]
+  fn test_fetch_cached_after_unset() {
+    let mut settings = Settings::new();
+    let mut cache = FetchCache::new();
+    settings.set("port", 12345);
+    assert_eq!(settings.fetch_cached(&mut cache, "port"), Some(12345i));
+
+    settings.unset("port");
+    let port: Option<int> = settings.fetch_cached(&mut cache, "port");
+    assert_eq!(port, None)
+  }
+
+  #[test]
+  fn test_fetch_cached_other_settings() {
+    let mut settings = Settings::new();
+    let mut cache = FetchCache::new();
+    settings.set("port", 12345);
+    assert_eq!(settings.fetch_cached(&mut cache, "port"), Some(12345i));
+
+    let mut other = Settings::new();
+    other.set("port", 4000);
+    assert_eq!(other.fetch_cached(&mut cache, "port"), Some(4000i));
+    assert_eq!(settings.fetch_cached(&mut cache, "port"), Some(12345i));
+
+    let mut clone = settings.clone();
+    clone.set("port", 8080);
+    assert_eq!(clone.fetch_cached(&mut cache, "port"), Some(8080i));
+    assert_eq!(settings.fetch_cached(&mut cache, "port"), Some(12345i))
+  }
+
+  fn assert_share<T: Share>() {}
+
+  #[test]
+  fn test_settings_share() {
+    assert_share::<Settings>();
+    assert_share::<FrozenSettings>()
+  }
+
+  #[test]
+  fn test_builder() {
+    let settings = Settings::new()
+      .with("port", 8080)
+      .with("ip", "127.0.0.1")
+      .with_opt(optopt("p", "port", "The port to bind to", "eg: 4000"))
+      .build();
+
+    assert_eq!(settings.fetch("port"), Some(8080));
+    assert_eq!(settings.get_raw("ip"), Some("127.0.0.1".to_string()));
+    assert!(settings.usage_brief().as_slice().contains("--port"))
+  }
+
+  #[test]
+  fn test_source_of() {
+    os::setenv("KNOBSOURCE_ENVIRONMENT", "production");
+
+    let mut settings = Settings::new();
+    settings.opt(optopt("p", "port", "The port to bind to", "eg: 4000"));
+    let args = vec!("myprog".to_string(), "-p".to_string(), "3000".to_string());
+    assert!(settings.load_args(args).is_ok());
+    assert!(settings.load_file(&Path::new("src/knob/fixtures/settings.conf")).is_ok());
+    assert!(settings.load_env("KNOBSOURCE").is_ok());
+    settings.set("workers", 4);
+
+    assert_eq!(settings.source_of("port"), Some(CommandLineSource));
+    assert_eq!(settings.source_of("ip"), Some(FileSource));
+    assert_eq!(settings.source_of("environment"), Some(EnvSource));
+    assert_eq!(settings.source_of("workers"), Some(ManualSource));
+    assert_eq!(settings.source_of("missing"), None)
+  }
+
+  #[test]
+  fn test_validate_passing() {
+    let mut settings = Settings::new();
+    assert!(settings.validate("port", valid_port).is_ok());
+    assert!(settings.try_set("port", 12345).is_ok());
+    assert_eq!(settings.fetch("port"), Some(12345))
+  }
+
+  #[test]
+  fn test_validate_failing() {
+    let mut settings = Settings::new();
+    settings.set("port", 12345);
+    assert!(settings.validate("port", valid_port).is_ok());
+
+    let result = settings.try_set("port", 70000);
+    assert_eq!(result, Err(InvalidValue("port".to_string(), "70000 is not a port".to_string())));
+    assert_eq!(settings.fetch("port"), Some(12345))
+  }
+
+  #[test]
+  #[should_fail]
+  fn test_validate_failing_set() {
+    let mut settings = Settings::new();
+    assert!(settings.validate("port", valid_port).is_ok());
+    settings.set("port", "foobar");
+  }
+
+  #[test]
+  fn test_validate_existing_value() {
+    let mut settings = Settings::new();
+    settings.set("port", 0);
+    let result = settings.validate("port", valid_port);
+    assert_eq!(result, Err(InvalidValue("port".to_string(), "0 is not a port".to_string())))
+  }
+
+  #[test]
+  fn test_validate_load_args() {
+    let mut settings = Settings::new();
+    settings.opt(optopt("p", "port", "The port to bind to", "eg: 4000"));
+    assert!(settings.validate("port", valid_port).is_ok());
+
+    let args = vec!("myprog".to_string(), "-p".to_string(), "70000".to_string());
+    assert!(settings.load_args(args).is_err());
+    assert!(!settings.contains("port"))
+  }
+
+  #[test]
+  fn test_fetch_int() {
+    let mut settings = Settings::new();
+    settings.set("offset", -12);
+    assert_eq!(settings.fetch_int("offset"), Some(-12));
+    assert_eq!(settings.fetch_int("missing"), None)
+  }
+
+  #[test]
+  fn test_fetch_uint() {
+    let mut settings = Settings::new();
+    settings.set("port", 12345u);
+    assert_eq!(settings.fetch_uint("port"), Some(12345u));
+    assert_eq!(settings.fetch_uint("missing"), None)
+  }
+
+  #[test]
+  fn test_fetch_bool() {
+    let mut settings = Settings::new();
+    settings.set("verbose", true);
+    assert_eq!(settings.fetch_bool("verbose"), Some(true));
+    assert_eq!(settings.fetch_bool("missing"), None)
+  }
+
+  #[test]
+  fn test_fetch_string() {
+    let mut settings = Settings::new();
+    settings.set("environment", "production");
+    assert_eq!(settings.fetch_string("environment"), Some("production".to_string()));
+    assert_eq!(settings.fetch_string("missing"), None)
+  }
+
+  #[test]
+  fn test_load_toml() {
+    let mut settings = Settings::new();
+    let result = settings.load_toml("# the settings\n\
+                                     port = 12_345\n\
+                                     environment = \"production\" # where to run\n\
+                                     color = \"#ff0000\"\n\
+                                     verbose = true\n");
+
+    assert!(result.is_ok());
+    assert_eq!(settings.fetch("port"), Some(12345));
+    assert_eq!(settings.get_raw("environment"), Some("production".to_string()));
+    assert_eq!(settings.get_raw("color"), Some("#ff0000".to_string()));
+    assert_eq!(settings.fetch("verbose"), Some(true))
+  }
+
+  #[test]
+  fn test_load_toml_section() {
+    let mut settings = Settings::new();
+    let result = settings.load_toml("port = 12345\n\
+                                     \n\
+                                     [db]\n\
+                                     host = 'localhost'\n\
+                                     port = 5432\n");
+
+    assert!(result.is_ok());
+    assert_eq!(settings.fetch("port"), Some(12345));
+    assert_eq!(settings.get_raw("db.host"), Some("localhost".to_string()));
+    assert_eq!(settings.fetch("db.port"), Some(5432))
+  }
+
+  #[test]
+  fn test_load_toml_array() {
+    let mut settings = Settings::new();
+    let result = settings.load_toml("port = 12345\n\
+                                     [server]\n\
+                                     hosts = [\"a\", \"b\"]\n");
+
+    assert_eq!(result, Err(UnsupportedValue("server.hosts".to_string())));
+    assert!(!settings.contains("port"))
+  }
+
+  #[test]
+  fn test_load_toml_syntax_error() {
+    let mut settings = Settings::new();
+    let result = settings.load_toml("port = 12345\nenvironment \"production\"\n");
+    assert_eq!(result, Err(InvalidToml("line 2: expected a key = value pair".to_string())))
+  }
+
+  #[test]
+  fn test_load_json_layer() {
+    let mut settings = Settings::new();
+    settings.set_layered("port", 8080u, CliLayer);
+    assert!(settings.load_json(r#"{"port": 12345, "environment": "production"}"#).is_ok());
+
+    assert_eq!(settings.fetch("port"), Some(8080u));
+    assert_eq!(settings.source_of("environment"), Some(FileSource));
+    settings.set_layered("environment", "staging", DefaultLayer);
+    assert_eq!(settings.get_raw("environment"), Some("production".to_string()))
+  }
+
+  #[test]
+  fn test_load_toml_layer() {
+    let mut settings = Settings::new();
+    settings.set_layered("port", 8080u, CliLayer);
+    assert!(settings.load_toml("port = 12345\nenvironment = \"production\"\n").is_ok());
+
+    assert_eq!(settings.fetch("port"), Some(8080u));
+    assert_eq!(settings.source_of("environment"), Some(FileSource));
+    settings.set_layered("environment", "staging", DefaultLayer);
+    assert_eq!(settings.get_raw("environment"), Some("production".to_string()))
+  }
+
+  #[test]
+  fn test_load_dotenv() {
+    let mut settings = Settings::new();
+    let result = settings.load_dotenv(&Path::new("src/knob/fixtures/settings.env"));
+
+    assert!(result.is_ok());
+    assert_eq!(settings.get_raw("DATABASE_URL"), Some("postgres://localhost/knob".to_string()));
+    assert_eq!(settings.fetch("PORT"), Some(12345));
+    assert!(!settings.contains("port"));
+    assert!(!settings.contains("export PORT"))
+  }
+
+  #[test]
+  fn test_load_dotenv_quotes() {
+    let mut settings = Settings::new();
+    assert!(settings.load_dotenv(&Path::new("src/knob/fixtures/settings.env")).is_ok());
+
+    assert_eq!(settings.get_raw("SECRET"), Some("s3cr#t value".to_string()));
+    assert_eq!(settings.get_raw("GREETING"), Some("hello world".to_string()))
+  }
+
+  #[test]
+  fn test_validate_types() {
+    let mut settings = Settings::new();
+    settings.set("port", 12345);
+    settings.set("workers", "many");
+    settings.set("verbose", "yes");
+
+    let specs: &[(&str, TypeCheck)] = [("port", parses::<u16>),
+                                       ("workers", parses::<uint>),
+                                       ("verbose", parses::<bool>),
+                                       ("missing", parses::<int>)];
+    let errors = settings.validate_types(specs);
+
+    assert_eq!(errors, vec!(("workers".to_string(), "many".to_string()),
+                            ("verbose".to_string(), "yes".to_string())))
+  }
+
+  #[test]
+  fn test_diff() {
+    let mut old = Settings::new();
+    old.set("port", 12345);
+    old.set("ip", "127.0.0.1");
+    old.set("environment", "production");
+
+    let mut new = Settings::new();
+    new.set("port", 4000);
+    new.set("environment", "production");
+    new.set("workers", 4);
+
+    assert_eq!(old.diff(&new), vec!(Modified("port".to_string(), "12345".to_string(), "4000".to_string()),
+                                    Removed("ip".to_string()),
+                                    Added("workers".to_string())))
+  }
+
+  #[test]
+  fn test_diff_equal() {
+    let mut settings = Settings::new();
+    settings.set("port", 12345);
+    assert_eq!(settings.diff(&settings.clone()), vec!())
+  }
+
+  #[test]
+  fn test_opt_bool() {
+    let mut settings = Settings::new();
+    settings.opt_bool("c", "color", "Colorize the output");
+
+    assert!(settings.load_args(vec!("myprog".to_string(), "--color".to_string())).is_ok());
+    assert_eq!(settings.fetch_bool("color"), Some(true));
+
+    assert!(settings.load_args(vec!("myprog".to_string(), "--no-color".to_string())).is_ok());
+    assert_eq!(settings.fetch_bool("color"), Some(false));
+    assert!(!settings.contains("no-color"))
+  }
+
+  #[test]
+  fn test_opt_bool_last_wins() {
+    let mut settings = Settings::new();
+    settings.opt_bool("c", "color", "Colorize the output");
+
+    let args = vec!("myprog".to_string(), "--color".to_string(), "--no-color".to_string());
+    assert!(settings.load_args(args).is_ok());
+    assert_eq!(settings.fetch_bool("color"), Some(false));
+
+    let args = vec!("myprog".to_string(), "--no-color".to_string(), "-c".to_string());
+    assert!(settings.load_args(args).is_ok());
+    assert_eq!(settings.fetch_bool("color"), Some(true))
+  }
+
+  #[test]
+  fn test_opt_bool_option_value() {
+    let mut settings = Settings::new();
+    settings.opt_bool("c", "color", "Colorize the output");
+    settings.opt(optopt("o", "output", "The file to write to", "FILE"));
+
+    let args = vec!("myprog".to_string(), "--no-color".to_string(), "-o/tmp/cache.txt".to_string());
+    assert!(settings.load_args(args).is_ok());
+    assert_eq!(settings.fetch_bool("color"), Some(false));
+
+    let args = vec!("myprog".to_string(), "--no-color".to_string(), "-o".to_string(), "-c".to_string());
+    assert!(settings.load_args(args).is_ok());
+    assert_eq!(settings.fetch_bool("color"), Some(false));
+    assert_eq!(settings.get_raw("output"), Some("-c".to_string()))
+  }
+
+  #[test]
+  fn test_opt_bool_omitted() {
+    let mut settings = Settings::new();
+    settings.opt_bool("c", "color", "Colorize the output");
+
+    assert!(settings.load_args(vec!("myprog".to_string())).is_ok());
+    assert!(!settings.contains("color"))
+  }
+
+  #[test]
+  fn test_describe_options() {
+    let mut settings = Settings::new();
+    settings.opt(reqopt("p", "port", "the port to bind to", "4000"));
+    settings.opt(optflag("v", "verbose", "be verbose"));
+
+    let options = settings.describe_options();
+    assert_eq!(options.len(), 2);
+    assert_eq!(options[0].long.as_slice(), "port");
+    assert!(options[0].required);
+    assert_eq!(options[1].long.as_slice(), "verbose");
+    assert!(!options[1].required);
+    assert_eq!(options[1], OptionInfo {
+      short: "v".to_string(),
+      long: "verbose".to_string(),
+      description: "be verbose".to_string(),
+      hint: "".to_string(),
+      required: false,
+    })
+  }
+
+  #[test]
+  fn test_bash_completion() {
+    let mut settings = Settings::new();
+    settings.opt(optopt("p", "port", "the port to bind to", "4000"));
+    settings.opt(optflag("", "verbose", "be verbose"));
+
+    let script = settings.bash_completion("myprog");
+    assert!(script.as_slice().contains("\"--port -p --verbose\""));
+    assert!(script.as_slice().contains("complete -F _myprog myprog"))
+  }
+
+  #[test]
+  fn test_fetch_path() {
+    let mut settings = Settings::new();
+    settings.set("config", "/etc/myprog/config.toml");
+    assert_eq!(settings.fetch_path("config"), Some(Path::new("/etc/myprog/config.toml")));
+    assert_eq!(settings.fetch_path_expanded("config"), Some(Path::new("/etc/myprog/config.toml")));
+    assert_eq!(settings.fetch_path("missing"), None)
+  }
+
+  #[test]
+  fn test_fetch_path_expanded() {
+    let mut settings = Settings::new();
+    settings.set("config", "~/.myprog");
+    let home = os::homedir().unwrap();
+    assert_eq!(settings.fetch_path("config"), Some(Path::new("~/.myprog")));
+    assert_eq!(settings.fetch_path_expanded("config"), Some(home.join(".myprog")))
+  }
+
+  #[test]
+  fn test_require() {
+    let mut settings = Settings::new();
+    settings.set("host", "localhost");
+    assert_eq!(settings.require(["host"]), Ok(()));
+    assert_eq!(settings.require(["host", "port"]), Err(vec!("port".to_string())))
+  }
+
+  #[test]
+  fn test_interpolate() {
+    let mut settings = Settings::new();
+    settings.set("url", "http://${host}:${port}/");
+    settings.set("host", "${domain}");
+    settings.set("domain", "example.com");
+    settings.set("port", 8080i);
+    settings.set("price", "$5");
+    assert!(settings.interpolate().is_ok());
+    assert_eq!(settings.fetch_string("url"), Some("http://example.com:8080/".to_string()));
+    assert_eq!(settings.fetch_string("host"), Some("example.com".to_string()));
+    assert_eq!(settings.fetch_string("price"), Some("$5".to_string()))
+  }
+
+  #[test]
+  fn test_interpolate_missing_reference() {
+    let mut settings = Settings::new();
+    settings.set("url", "http://${host}/");
+    assert_eq!(settings.interpolate(), Err(MissingReference("host".to_string())));
+    assert_eq!(settings.fetch_string("url"), Some("http://${host}/".to_string()))
+  }
+
+  #[test]
+  fn test_interpolate_cycle() {
+    let mut settings = Settings::new();
+    settings.set("a", "${b}");
+    settings.set("b", "${a}");
+    assert_eq!(settings.interpolate(), Err(CyclicReference("a".to_string())))
+  }
+
+  #[test]
+  fn test_fetch_with_result() {
+    let mut settings = Settings::new();
+    settings.set("port", "not a port");
+    let port: uint = settings.fetch_with_result("port", |result| {
+      match result {
+        Ok(Some(port)) => { port },
+        Ok(None) => { 1000 },
+        Err(raw) => {
+          assert_eq!(raw.as_slice(), "not a port");
+          2000
+        }
+      }
+    });
+    assert_eq!(port, 2000);
+
+    let timeout: uint = settings.fetch_with_result("timeout", |result| {
+      assert_eq!(result, Ok(None));
+      30
+    });
+    assert_eq!(timeout, 30)
+  }
+
+  #[test]
+  fn test_fetch_prefix() {
+    let mut settings = Settings::new();
+    settings.set("db", "primary");
+    settings.set("db.host", "localhost");
+    settings.set("db.port", 5432i);
+    settings.set("db.name", "knob");
+    settings.set("dbx.host", "example.com");
+    settings.set("port", 8080i);
+
+    let db = settings.fetch_prefix("db", false);
+    assert_eq!(db.len(), 3);
+    assert_eq!(db.find_equiv(&"db.host"), Some(&"localhost".to_string()));
+
+    let db = settings.fetch_prefix("db", true);
+    assert_eq!(db.len(), 3);
+    assert_eq!(db.find_equiv(&"port"), Some(&"5432".to_string()));
+    assert_eq!(db.find_equiv(&"name"), Some(&"knob".to_string()));
+    assert_eq!(db.find_equiv(&"host"), Some(&"localhost".to_string()))
+  }
+
+  #[test]
+  fn test_from_iterator() {
+    let pairs = vec!(("port".to_string(), "8080".to_string()),
+                     ("host".to_string(), "localhost".to_string()));
+    let settings: Settings = pairs.move_iter().collect();
+    assert_eq!(settings.fetch_int("port"), Some(8080));
+    assert_eq!(settings.fetch_string("host"), Some("localhost".to_string()));
+    assert_eq!(settings.usage_brief(), "".to_string())
+  }
+
+  #[test]
+  fn test_load_file_reporting() {
+    let mut settings = Settings::new();
+    settings.set_default("port", 8080i);
+    settings.set_default("ip", "127.0.0.1");
+    settings.set_default("environment", "production");
+
+    let overridden = settings.load_file_reporting(&Path::new("src/knob/fixtures/settings.conf"));
+    assert_eq!(overridden.unwrap(),
+               vec!(("port".to_string(), "8080".to_string(), "12345".to_string())));
+    assert_eq!(settings.fetch_int("port"), Some(12345))
+  }
+
+  #[test]
+  fn test_save_file_with_header() {
+    let dir = TempDir::new("knob").unwrap();
+    let path = dir.path().join("settings.conf");
+
+    let mut settings = Settings::new();
+    settings.set("port", 12345);
+    settings.set("ip", "127.0.0.1");
+    assert!(settings.save_file_with_header(&path, "Generated by myprog.\nDo not edit.").is_ok());
+
+    let contents = File::open(&path).read_to_str().unwrap();
+    assert_eq!(contents.as_slice(),
+               "# Generated by myprog.\n# Do not edit.\n\nip=127.0.0.1\nport=12345\n");
+
+    let mut loaded = Settings::new();
+    assert!(loaded.load_file(&path).is_ok());
+    assert_eq!(loaded.fetch_int("port"), Some(12345))
+  }
+
+  #[test]
+  fn test_fetch_bool_spellings() {
+    let mut settings = Settings::new();
+    settings.set("a", "yes");
+    settings.set("b", "OFF");
+    settings.set("c", 1i);
+    settings.set("d", "garbage");
+    settings.set("e", "");
+    assert_eq!(settings.fetch_bool("a"), Some(true));
+    assert_eq!(settings.fetch_bool("b"), Some(false));
+    assert_eq!(settings.fetch_bool("c"), Some(true));
+    assert_eq!(settings.fetch_bool("d"), None);
+    assert_eq!(settings.fetch_bool("e"), None)
+  }
+
+  #[test]
+  fn test_parse_bool() {
+    assert_eq!(parse_bool("True"), Some(true));
+    assert_eq!(parse_bool("no"), Some(false));
+    assert_eq!(parse_bool("0"), Some(false));
+    assert_eq!(parse_bool("On"), Some(true));
+    assert_eq!(parse_bool("2"), None);
+    assert_eq!(parse_bool(""), None)
+  }
+
+  #[test]
+  fn test_fetch_any() {
+    let mut settings = Settings::new();
+    settings.set("bind", "127.0.0.1:8080");
+    settings.set("port", "not a port");
+    settings.set("legacy_port", 8080i);
+
+    let addr: Option<SocketAddr> = settings.fetch_any(["listen_addr", "bind"]);
+    assert_eq!(addr, from_str("127.0.0.1:8080"));
+    let port: Option<uint> = settings.fetch_any(["port", "legacy_port"]);
+    assert_eq!(port, Some(8080));
+    let missing: Option<uint> = settings.fetch_any(["timeout", "legacy_timeout"]);
+    assert_eq!(missing, None)
+  }
+
+  #[test]
+  fn test_fetch_duration() {
+    let mut settings = Settings::new();
+    settings.set("timeout", "30s");
+    settings.set("delay", "500ms");
+    settings.set("interval", "5m");
+    settings.set("invalid", "30x");
+    settings.set("bare", 30i);
+    assert_eq!(settings.fetch_duration("timeout"), Some(Duration::seconds(30)));
+    assert_eq!(settings.fetch_duration("delay"), Some(Duration::milliseconds(500)));
+    assert_eq!(settings.fetch_duration("interval"), Some(Duration::minutes(5)));
+    assert_eq!(settings.fetch_duration("invalid"), None);
+    assert_eq!(settings.fetch_duration("bare"), None);
+    assert_eq!(settings.fetch_duration("missing"), None)
+  }
+
+  #[test]
+  fn test_fetch_duration_overflow() {
+    let mut settings = Settings::new();
+    settings.set("timeout", "99999999999999999d");
+    settings.set("delay", "99999999999999999999ms");
+    assert_eq!(settings.fetch_duration("timeout"), None);
+    assert_eq!(settings.fetch_duration("delay"), None)
+  }
+
+  #[test]
+  fn test_snapshot_restore() {
+    let mut settings = Settings::new();
+    settings.set_default("port", 8080i);
+    settings.set_default("host", "localhost");
+    let defaults = settings.snapshot();
+
+    settings.set("port", 9090i);
+    settings.set("verbose", true);
+    settings.unset("host");
+    settings.restore(&defaults);
+
+    assert_eq!(settings.fetch_int("port"), Some(8080));
+    assert_eq!(settings.fetch_string("host"), Some("localhost".to_string()));
+    assert!(!settings.contains("verbose"));
+    assert_eq!(settings.len(), 2)
+  }
+
+  #[test]
+  fn test_unset_options() {
+    let mut settings = Settings::new();
+    settings.opt(optopt("p", "port", "the port to bind to", "4000"));
+    settings.opt(optopt("", "host", "the host to bind to", "localhost"));
+    settings.opt_bool("c", "color", "Colorize the output");
+
+    let args = vec!("myprog".to_string(), "--port".to_string(), "4000".to_string());
+    assert!(settings.load_args(args).is_ok());
+    assert_eq!(settings.unset_options(), vec!("host".to_string(), "color".to_string()));
+
+    settings.set_default("host", "localhost");
+    assert_eq!(settings.unset_options(), vec!("color".to_string()))
+  }
+
+  #[test]
+  fn test_fetch_int_radix() {
+    let mut settings = Settings::new();
+    settings.set("million", "1_000_000");
+    settings.set("hex", "0x1F");
+    settings.set("octal", "0o17");
+    settings.set("binary", "0b1010_1010");
+    settings.set("negative", "-0x10");
+    settings.set("plain", 42i);
+    settings.set("prefix", "0x");
+    settings.set("garbage", "0xZZ");
+    assert_eq!(settings.fetch_int_radix("million"), Some(1000000));
+    assert_eq!(settings.fetch_int_radix("hex"), Some(31));
+    assert_eq!(settings.fetch_int_radix("octal"), Some(15));
+    assert_eq!(settings.fetch_int_radix("binary"), Some(170));
+    assert_eq!(settings.fetch_int_radix("negative"), Some(-16));
+    assert_eq!(settings.fetch_int_radix("plain"), Some(42));
+    assert_eq!(settings.fetch_int_radix("prefix"), None);
+    assert_eq!(settings.fetch_int_radix("garbage"), None);
+    assert_eq!(settings.fetch_int_radix("missing"), None)
+  }
+
+  enum CustomKeys {
+    DatabaseUrl,
+  }
+
+  impl Key for CustomKeys {
+    fn as_key(&self) -> String {
+      match *self {
+        DatabaseUrl => { "database.url".to_string() }
+      }
+    }
+  }
+
+  #[test]
+  fn test_custom_key() {
+    let mut settings = Settings::new();
+    settings.set(DatabaseUrl, "postgres://localhost/knob");
+    assert_eq!(DatabaseUrl.as_key(), "database.url".to_string());
+    assert_eq!(settings.fetch_string("database.url"), Some("postgres://localhost/knob".to_string()));
+    assert_eq!(settings.fetch_string(DatabaseUrl), Some("postgres://localhost/knob".to_string()));
+    assert_eq!(Port.as_key(), Port.to_str())
+  }
+
+  #[test]
+  fn test_rename() {
+    let mut settings = Settings::new();
+    settings.set_layered("bind", "127.0.0.1:8080", FileLayer);
+    assert!(settings.rename("bind", "listen_addr"));
+    assert!(!settings.contains("bind"));
+    assert_eq!(settings.fetch_string("listen_addr"), Some("127.0.0.1:8080".to_string()));
+    assert_eq!(settings.source_of("listen_addr"), Some(FileSource))
+  }
+
+  #[test]
+  fn test_rename_missing() {
+    let mut settings = Settings::new();
+    settings.set("listen_addr", "127.0.0.1:8080");
+    assert!(!settings.rename("bind", "listen_addr"));
+    assert_eq!(settings.fetch_string("listen_addr"), Some("127.0.0.1:8080".to_string()))
+  }
+
+  #[test]
+  fn test_rename_overwrite() {
+    let mut settings = Settings::new();
+    settings.set("bind", "0.0.0.0:80");
+    settings.set("listen_addr", "127.0.0.1:8080");
+    assert!(settings.rename("bind", "listen_addr"));
+    assert_eq!(settings.fetch_string("listen_addr"), Some("0.0.0.0:80".to_string()));
+    assert_eq!(settings.len(), 1)
+  }
+
+  #[test]
+  fn test_load_files() {
+    let mut settings = Settings::new();
+    let results = settings.load_files([Path::new("src/knob/fixtures/settings.conf"),
+                                       Path::new("src/knob/fixtures/missing.conf"),
+                                       Path::new("src/knob/fixtures/override.conf")]);
+    assert_eq!(results.len(), 3);
+    assert!(results[0].ref1().is_ok());
+    assert_eq!(results[1].ref0(), &Path::new("src/knob/fixtures/missing.conf"));
+    assert!(results[1].ref1().is_err());
+    assert!(results[2].ref1().is_ok());
+
+    assert_eq!(settings.fetch_int("port"), Some(54321));
+    assert_eq!(settings.fetch_string("ip"), Some("127.0.0.1".to_string()));
+    assert_eq!(settings.fetch_string("environment"), Some("staging".to_string()))
+  }
+
+  #[test]
+  fn test_get() {
+    let mut settings = Settings::new();
+    settings.set("port", 8080i);
+    settings.set("timeout", "soon");
+    assert_eq!(settings.get::<&str, uint>("port"), Ok(8080));
+    assert_eq!(settings.get::<&str, uint>("host"), Err(Missing("host".to_string())));
+    assert_eq!(settings.get::<&str, uint>("timeout"),
+               Err(Parse("timeout".to_string(), "soon".to_string())))
+  }
+
+  #[test]
+  fn test_fetch_csv() {
+    let mut settings = Settings::new();
+    settings.set("ports", "1, 2, 3");
+    settings.set("hosts", "a.example.com,b.example.com,");
+    settings.set("host", "localhost");
+    settings.set("empty", "");
+    assert_eq!(settings.fetch_csv("ports"), Some(vec!(1u, 2, 3)));
+    assert_eq!(settings.fetch_csv("hosts"),
+               Some(vec!("a.example.com".to_string(), "b.example.com".to_string())));
+    assert_eq!(settings.fetch_csv("host"), Some(vec!("localhost".to_string())));
+    assert_eq!(settings.fetch_csv::<&str, String>("empty"), Some(vec!()));
+    assert_eq!(settings.fetch_csv::<&str, String>("missing"), None)
+  }
+
+  #[test]
+  #[should_fail]
+  fn test_fetch_csv_invalid() {
+    let mut settings = Settings::new();
+    settings.set("ports", "1, two, 3");
+    settings.fetch_csv::<&str, uint>("ports");
+  }
+
+  #[test]
+  fn test_load_args_if_absent() {
+    let mut settings = Settings::new();
+    settings.opt(optopt("p", "port", "the port to bind to", "4000"));
+    settings.opt(optopt("", "host", "the host to bind to", "localhost"));
+    settings.set_layered("port", 8080i, FileLayer);
+
+    let args = vec!("myprog".to_string(), "--port".to_string(), "4000".to_string(),
+                    "--host".to_string(), "example.com".to_string());
+    assert!(settings.load_args_if_absent(args).is_ok());
+    assert_eq!(settings.fetch_int("port"), Some(8080));
+    assert_eq!(settings.fetch_string("host"), Some("example.com".to_string()))
+  }
+
+  #[test]
+  fn test_parse_error_count() {
+    let mut settings = Settings::new();
+    settings.set("port", "not a port");
+    settings.set("timeout", 30i);
+    assert_eq!(settings.parse_error_count(), 0);
+
+    assert!(settings.try_fetch::<&str, uint>("port").is_err());
+    assert!(settings.try_fetch::<&str, uint>("timeout").is_ok());
+    assert!(settings.try_fetch::<&str, int>("port").is_err());
+    assert_eq!(settings.parse_error_count(), 2)
+  }
+
+  #[test]
+  fn test_env_fallback() {
+    os::setenv("KNOB_FALLBACK_TEST_PORT", "4000");
+    let mut settings = Settings::new();
+    assert_eq!(settings.fetch_int("port"), None);
+
+    settings.enable_env_fallback("KNOB_FALLBACK_TEST");
+    assert_eq!(settings.fetch_int("port"), Some(4000));
+    assert!(!settings.contains("port"));
+
+    settings.set("port", 8080i);
+    assert_eq!(settings.fetch_int("port"), Some(8080));
+    os::unsetenv("KNOB_FALLBACK_TEST_PORT")
+  }
+
+  #[test]
+  fn test_env_fallback_fetch_variants() {
+    os::setenv("KNOB_VARIANTS_TEST_VERBOSE", "yes");
+    os::setenv("KNOB_VARIANTS_TEST_TIMEOUT", "30s");
+    os::setenv("KNOB_VARIANTS_TEST_HOSTS", "a.example.com, b.example.com");
+    os::setenv("KNOB_VARIANTS_TEST_RATE", "44100 Hz");
+    let mut settings = Settings::new();
+    settings.enable_env_fallback("KNOB_VARIANTS_TEST");
+
+    assert_eq!(settings.fetch_bool("verbose"), Some(true));
+    assert_eq!(settings.fetch_duration("timeout"), Some(Duration::seconds(30)));
+    assert_eq!(settings.fetch_csv("hosts"),
+               Some(vec!("a.example.com".to_string(), "b.example.com".to_string())));
+    assert_eq!(settings.fetch_f64_unit("rate", "Hz"), Some(44100.0));
+    assert!(!settings.contains("verbose"));
+    os::unsetenv("KNOB_VARIANTS_TEST_VERBOSE");
+    os::unsetenv("KNOB_VARIANTS_TEST_TIMEOUT");
+    os::unsetenv("KNOB_VARIANTS_TEST_HOSTS");
+    os::unsetenv("KNOB_VARIANTS_TEST_RATE")
+  }
+
+  #[test]
+  fn test_push() {
+    let mut settings = Settings::new();
+    settings.push("hosts", "a.example.com");
+    assert_eq!(settings.fetch_list("hosts"), Some(vec!("a.example.com".to_string())));
+
+    settings.set_list("ports", [80u, 443]);
+    settings.push("ports", 8080u);
+    assert_eq!(settings.fetch_list("ports"), Some(vec!(80u, 443, 8080)));
+
+    settings.set("port", 80u);
+    settings.push("port", 443u);
+    assert_eq!(settings.fetch_list("port"), Some(vec!(80u, 443)))
+  }
+
+  #[test]
+  fn test_lock() {
+    let mut settings = Settings::new();
+    settings.set("environment", "staging");
+    settings.lock("environment");
+    assert!(settings.is_locked("environment"));
+
+    settings.set("environment", "production");
+    settings.set_layered("environment", "production", CliLayer);
+    settings.extend(vec!(("environment", "production")).move_iter());
+    assert_eq!(settings.fetch_string("environment"), Some("staging".to_string()));
+
+    settings.unlock("environment");
+    assert!(!settings.is_locked("environment"));
+    settings.set("environment", "production");
+    assert_eq!(settings.fetch_string("environment"), Some("production".to_string()))
+  }
+
+  #[test]
+  fn test_lock_unset_rename() {
+    let mut settings = Settings::new();
+    settings.set("environment", "staging");
+    settings.set("stage", "production");
+    settings.lock("environment");
+
+    assert_eq!(settings.unset("environment"), None);
+    assert!(!settings.rename("environment", "env"));
+    assert!(!settings.rename("stage", "environment"));
+    assert_eq!(settings.fetch_string("environment"), Some("staging".to_string()));
+    assert_eq!(settings.fetch_string("stage"), Some("production".to_string()));
+
+    settings.clear();
+    assert_eq!(settings.fetch_string("environment"), Some("staging".to_string()));
+    assert!(!settings.contains("stage"))
+  }
+
+  #[test]
+  fn test_lock_merge_restore() {
+    let mut settings = Settings::new();
+    settings.set("environment", "staging");
+    let snapshot = settings.snapshot();
+    settings.set("environment", "qa");
+    settings.lock("environment");
+
+    let mut other = Settings::new();
+    other.set("environment", "production");
+    settings.merge(&other);
+    assert_eq!(settings.fetch_string("environment"), Some("qa".to_string()));
+
+    settings.restore(&snapshot);
+    assert_eq!(settings.fetch_string("environment"), Some("qa".to_string()));
+
+    settings.lock("port");
+    let mut other = Settings::new();
+    other.set("port", 8080u);
+    settings.restore(&other.snapshot());
+    assert!(!settings.contains("port"))
+  }
+
+  #[test]
+  fn test_lock_expand() {
+    let mut settings = Settings::new();
+    settings.set("home", "${HOME}");
+    settings.lock("home");
+    settings.expand_env(false);
+    assert_eq!(settings.fetch_string("home"), Some("${HOME}".to_string()));
+
+    let mut settings = Settings::new();
+    settings.set("home", "/home/knob");
+    settings.set("path", "${home}/bin");
+    settings.set("cache", "${home}/.cache");
+    settings.lock("path");
+    assert!(settings.interpolate().is_ok());
+    assert_eq!(settings.fetch_string("path"), Some("${home}/bin".to_string()));
+    assert_eq!(settings.fetch_string("cache"), Some("/home/knob/.cache".to_string()))
+  }
+
+  #[test]
+  fn test_lock_case_insensitive() {
+    let mut settings = Settings::new();
+    settings.set("Environment", "staging");
+    settings.lock("Environment");
+    settings.set_case_insensitive(true);
+
+    assert!(settings.is_locked("environment"));
+    settings.set("ENVIRONMENT", "production");
+    assert_eq!(settings.fetch_string("environment"), Some("staging".to_string()))
+  }
+
+  #[test]
+  fn test_load_assignments() {
+    let mut settings = Settings::new();
+    let args = vec!("db.host=localhost".to_string(), "run".to_string(),
+                    "db.port=5432".to_string(), "query=a=b".to_string(), "=value".to_string());
+    assert!(settings.load_assignments(args.as_slice()).is_ok());
+    assert_eq!(settings.len(), 3);
+    assert_eq!(settings.fetch_string("db.host"), Some("localhost".to_string()));
+    assert_eq!(settings.fetch_int("db.port"), Some(5432));
+    assert_eq!(settings.fetch_string("query"), Some("a=b".to_string()));
+    assert_eq!(settings.source_of("db.host"), Some(CommandLineSource))
+  }
+
+  #[test]
+  fn test_strict_file() {
+    let mut settings = Settings::new();
+    settings.allow_keys(["port", "ip"]);
+    assert!(settings.load_file(&Path::new("src/knob/fixtures/misspelled.conf")).is_ok());
+
+    let mut settings = Settings::new();
+    settings.allow_keys(["port", "ip"]);
+    settings.set_strict(true);
+    let error = settings.load_file(&Path::new("src/knob/fixtures/misspelled.conf")).unwrap_err();
+    assert_eq!(error.detail, Some("prot".to_string()));
+    assert_eq!(settings.fetch_int("port"), Some(12345));
+    assert!(!settings.contains("prot"))
+  }
+
+  #[test]
+  fn test_strict_json() {
+    let mut settings = Settings::new();
+    settings.allow_keys(["port"]);
+    settings.set_strict(true);
+    assert_eq!(settings.load_json(r#"{"port": 8080, "prot": 8081}"#),
+               Err(UnknownKey("prot".to_string())));
+    assert!(!settings.contains("port"))
+  }
+
+  #[test]
+  fn test_strict_case_insensitive() {
+    let mut settings = Settings::new();
+    settings.allow_keys(["Port"]);
+    settings.set_strict(true);
+    settings.set_case_insensitive(true);
+    assert!(settings.load_toml("port = 1\n").is_ok());
+    assert!(settings.load_json(r#"{"PORT": 2}"#).is_ok());
+    assert_eq!(settings.fetch_int("port"), Some(2))
+  }
+
+  #[test]
+  fn test_options_case_insensitive() {
+    let mut settings = Settings::new();
+    settings.opt_alias(optopt("", "Listen", "The address to listen on", "ADDR"), "Bind");
+    settings.opt_default(optopt("", "Port", "The port to bind to", "PORT"), "4000");
+    settings.opt_bool("", "Color", "Colorize the output");
+    settings.set_case_insensitive(true);
+
+    let args = vec!("myprog".to_string(), "--Listen".to_string(), "0.0.0.0:80".to_string(),
+                    "--no-Color".to_string());
+    assert!(settings.load_args(args).is_ok());
+    assert_eq!(settings.get_raw("bind"), Some("0.0.0.0:80".to_string()));
+    assert_eq!(settings.fetch_int("port"), Some(4000));
+    assert_eq!(settings.fetch_bool("color"), Some(false));
+    assert!(!settings.contains("no-color"))
+  }
+
+  #[test]
+  fn test_strict_toml() {
+    let mut settings = Settings::new();
+    settings.allow_keys(["port"]);
+    settings.set_strict(true);
+    assert_eq!(settings.load_toml("port = 8080\nprot = 8081\n"),
+               Err(UnknownKey("prot".to_string())));
+    assert!(!settings.contains("port"))
+  }
+
+  #[test]
+  fn test_strict_dotenv() {
+    let mut settings = Settings::new();
+    settings.allow_keys(["DATABASE_URL"]);
+    settings.set_strict(true);
+    let error = settings.load_dotenv(&Path::new("src/knob/fixtures/settings.env")).unwrap_err();
+    assert_eq!(error.detail, Some("PORT".to_string()));
+    assert!(settings.contains("DATABASE_URL"));
+    assert!(!settings.contains("PORT"))
+  }
+
+  #[deriving(Show, PartialEq, Clone)]
+  enum LogLevel {
+    Info,
+    Warn,
+    Fatal,
+  }
+
+  #[test]
+  fn test_fetch_enum() {
+    let levels = [("info", Info), ("warn", Warn), ("fatal", Fatal)];
+    let mut settings = Settings::new();
+    settings.set("log_level", "warn");
+    settings.set("verbosity", "WARN");
+    settings.set("color", "loud");
+    assert_eq!(settings.fetch_enum("log_level", levels), Some(Warn));
+    assert_eq!(settings.fetch_enum("verbosity", levels), Some(Warn));
+    assert_eq!(settings.fetch_enum("color", levels), None);
+    assert_eq!(settings.fetch_enum("missing", levels), None)
+  }
+
+  #[test]
+  fn test_format_table() {
+    let mut settings = Settings::new();
+    assert!(settings.load_args(vec!("myprog".to_string())).is_ok());
+    settings.set("port", 8080i);
+    settings.set("database_url", "postgres://localhost/knob");
+    settings.set("ip", "127.0.0.1");
+
+    assert_eq!(settings.format_table(80).as_slice(),
+               "database_url  postgres://localhost/knob\n\
+                ip            127.0.0.1\n\
+                port          8080\n");
+    assert_eq!(settings.format_table(12).as_slice(),
+               "database_url  postgres:...\n\
+                ip            127.0.0.1\n\
+                port          8080\n")
+  }
+
+  #[test]
+  fn test_format_table_narrow() {
+    let mut settings = Settings::new();
+    settings.set("port", 8080i);
+    settings.set("ip", "127.0.0.1");
+
+    assert_eq!(settings.format_table(2).as_slice(),
+               "ip    12\n\
+                port  80\n");
+    assert_eq!(settings.format_table(0).as_slice(),
+               "ip    \n\
+                port  \n")
+  }
+
+  #[test]
+  fn test_require_get() {
+    let mut settings = Settings::new();
+    settings.set("port", "foobar");
+    settings.set("timeout", 30u);
+    assert_eq!(settings.require_get::<&str, uint>("timeout"), Ok(30));
+    assert_eq!(settings.require_get::<&str, uint>("host"),
+               Err("missing required setting 'host'".to_string()));
+    assert_eq!(settings.require_get::<&str, uint>("port"),
+               Err("invalid value for 'port': 'foobar'".to_string()))
+  }
+
+  #[test]
+  fn test_load_yaml() {
+    let mut settings = Settings::new();
+    let result = settings.load_yaml("---\n\
+                                     # the settings\n\
+                                     port: 12345\n\
+                                     environment: \"production\" # where to run\n\
+                                     color: '#ff0000'\n\
+                                     motto: it's number#1\n");
+
+    assert!(result.is_ok());
+    assert_eq!(settings.fetch("port"), Some(12345));
+    assert_eq!(settings.get_raw("environment"), Some("production".to_string()));
+    assert_eq!(settings.get_raw("color"), Some("#ff0000".to_string()));
+    assert_eq!(settings.get_raw("motto"), Some("it's number#1".to_string()))
+  }
+
+  #[test]
+  fn test_load_yaml_nested() {
+    let mut settings = Settings::new();
+    let result = settings.load_yaml("port: 12345\n\
+                                     db:\n  \
+                                       host: localhost\n  \
+                                       port: 5432\n\
+                                     verbose: true\n");
+
+    assert!(result.is_ok());
+    assert_eq!(settings.fetch("port"), Some(12345));
+    assert_eq!(settings.get_raw("db.host"), Some("localhost".to_string()));
+    assert_eq!(settings.fetch("db.port"), Some(5432));
+    assert_eq!(settings.fetch("verbose"), Some(true));
+    assert!(!settings.contains("db"))
+  }
+
+  #[test]
+  fn test_load_yaml_sequence() {
+    let mut settings = Settings::new();
+    let result = settings.load_yaml("port: 12345\n\
+                                     hosts:\n  \
+                                       - a.example.com\n  \
+                                       - b.example.com\n");
+    assert_eq!(result, Err(UnsupportedValue("hosts".to_string())));
+    assert!(!settings.contains("port"));
+
+    let result = settings.load_yaml("hosts: [a.example.com, b.example.com]\n");
+    assert_eq!(result, Err(UnsupportedValue("hosts".to_string())));
+
+    let result = settings.load_yaml("db:\n  primary:\n    host: localhost\n");
+    assert_eq!(result, Err(UnsupportedValue("db.primary".to_string())))
+  }
+
+  #[test]
+  fn test_load_yaml_syntax_error() {
+    let mut settings = Settings::new();
+    let result = settings.load_yaml("port: 12345\nenvironment production\n");
+    assert_eq!(result, Err(InvalidYaml("line 2: expected a key: value pair".to_string())))
+  }
+
+  #[test]
+  fn test_load_yaml_layer() {
+    let mut settings = Settings::new();
+    settings.set_layered("port", 8080u, CliLayer);
+    assert!(settings.load_yaml("port: 12345\nenvironment: production\n").is_ok());
+
+    assert_eq!(settings.fetch("port"), Some(8080u));
+    assert_eq!(settings.source_of("environment"), Some(FileSource))
+  }
+
+  #[test]
+  fn test_strict_yaml() {
+    let mut settings = Settings::new();
+    settings.allow_keys(["port", "db.host"]);
+    settings.set_strict(true);
+    assert!(settings.load_yaml("db:\n  host: localhost\n").is_ok());
+    assert_eq!(settings.load_yaml("port: 8080\nprot: 8081\n"),
+               Err(UnknownKey("prot".to_string())));
+    assert!(!settings.contains("port"))
+  }
+
+  #[test]
+  fn test_opt_default() {
+    let mut settings = Settings::new();
+    settings.opt_default(optopt("p", "port", "the port to bind to", "4000"), "4000");
+    assert!(!settings.contains("port"));
+
+    assert!(settings.load_args(vec!("myprog".to_string())).is_ok());
+    assert_eq!(settings.fetch_int("port"), Some(4000));
+    assert_eq!(settings.source_of("port"), Some(ManualSource));
+
+    let args = vec!("myprog".to_string(), "--port".to_string(), "8080".to_string());
+    assert!(settings.load_args(args).is_ok());
+    assert_eq!(settings.fetch_int("port"), Some(8080));
+    assert_eq!(settings.source_of("port"), Some(CommandLineSource))
+  }
+
+  #[test]
+  fn test_opt_default_below_file() {
+    let mut settings = Settings::new();
+    settings.opt_default(optopt("p", "port", "the port to bind to", "4000"), "4000");
+    assert!(settings.load_file(&Path::new("src/knob/fixtures/settings.conf")).is_ok());
+    assert!(settings.load_args(vec!("myprog".to_string())).is_ok());
+    assert_eq!(settings.fetch_int("port"), Some(12345))
+  }
+
+  #[deriving(PartialEq)]
+  struct Lossy(f64);
+
+  impl fmt::Show for Lossy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+      let Lossy(value) = *self;
+      write!(f, "{}", value.round())
+    }
+  }
+
+  impl FromStr for Lossy {
+    fn from_str(value: &str) -> Option<Lossy> {
+      from_str(value).map(|value| Lossy(value))
+    }
+  }
+
+  #[test]
+  fn test_set_checked() {
+    let mut settings = Settings::new();
+    assert_eq!(settings.set_checked("port", 8080u), Ok(()));
+    assert_eq!(settings.fetch_int("port"), Some(8080));
+
+    assert_eq!(settings.set_checked("ratio", Lossy(1.5)),
+               Err("value for 'ratio' does not parse back: '2'".to_string()));
+    assert!(!settings.contains("ratio"));
+    assert_eq!(settings.set_checked("ratio", Lossy(2.0)), Ok(()))
+  }
+
+  #[test]
+  fn test_fetch_list_sep() {
+    let mut settings = Settings::new();
+    settings.set("commas", "1,2,3");
+    settings.set("spaces", "1 2  3");
+    settings.set("mixed", "1, 2;3 4;");
+    assert_eq!(settings.fetch_list_sep("commas", [',']), Some(vec!(1u, 2, 3)));
+    assert_eq!(settings.fetch_list_sep("spaces", [' ']), Some(vec!(1u, 2, 3)));
+    assert_eq!(settings.fetch_list_sep("mixed", [',', ';', ' ']), Some(vec!(1u, 2, 3, 4)));
+    assert_eq!(settings.fetch_list_sep::<&str, uint>("missing", [',']), None)
+  }
+
+  #[test]
+  fn test_on_change() {
+    let (sender, receiver) = channel();
+    let mut settings = Settings::new();
+    settings.on_change(sender);
+
+    settings.set("port", 8080i);
+    settings.set("port", 8080i);
+    settings.set_layered("port", 9090i, FileLayer);
+    settings.set_layered("port", 7070i, DefaultLayer);
+    assert!(settings.load_json(r#"{"host": "localhost"}"#).is_ok());
+
+    assert_eq!(receiver.recv(), ("port".to_string(), "8080".to_string()));
+    assert_eq!(receiver.recv(), ("port".to_string(), "9090".to_string()));
+    assert_eq!(receiver.recv(), ("host".to_string(), "localhost".to_string()));
+    assert!(receiver.try_recv().is_err())
+  }
+
+  #[test]
+  fn test_usage_without_options() {
+    let settings = Settings::new();
+    assert_eq!(settings.usage("Usage: myprog".to_string()), "Usage: myprog".to_string());
+    assert_eq!(settings.usage_wrapped("Usage: myprog", 40), "Usage: myprog".to_string());
+    assert_eq!(settings.usage_brief(), "".to_string())
+  }
+
+  #[test]
+  fn test_fetch_hierarchical() {
+    let mut settings = Settings::new();
+    settings.set("db.port", 5432i);
+    settings.set("port", 8080i);
+    settings.set("db.replica.host", "replica.example.com");
+    settings.set("timeout", 30i);
+
+    assert_eq!(settings.fetch_hierarchical("db.replica.port"), Some(5432i));
+    assert_eq!(settings.fetch_hierarchical("cache.port"), Some(8080i));
+    assert_eq!(settings.fetch_hierarchical("db.replica.timeout"), Some(30i));
+    assert_eq!(settings.fetch_hierarchical("db.replica.host"), Some("replica.example.com".to_string()));
+    assert_eq!(settings.fetch_hierarchical::<int>("db.replica.retries"), None)
+  }
+
+  #[test]
+  fn test_set_if() {
+    let mut settings = Settings::new();
+    settings.set_if("log_level", "debug", true);
+    settings.set_if("color", "never", false);
+    assert_eq!(settings.fetch_string("log_level"), Some("debug".to_string()));
+    assert!(!settings.contains("color"))
+  }
+
+  #[test]
+  fn test_provided_options() {
+    let mut settings = Settings::new();
+    settings.opt_default(optopt("p", "port", "the port to bind to", "4000"), "4000");
+    settings.opt(optopt("", "host", "the host to bind to", "localhost"));
+    settings.opt(optflag("v", "verbose", "be verbose"));
+    assert!(settings.provided_options().is_empty());
+
+    let args = vec!("myprog".to_string(), "-v".to_string());
+    assert!(settings.load_args(args).is_ok());
+    assert_eq!(settings.provided_options(), vec!("verbose".to_string()));
+    assert!(settings.contains("port"))
+  }
+
+  #[test]
+  fn test_fetch_normalized() {
+    let mut settings = Settings::new();
+    settings.set("color", "#ff0000");
+    let color: Option<u32> = settings.fetch_normalized("color", |value| {
+      value.trim_left_chars('#').to_string()
+    });
+    assert_eq!(color, None);
+
+    let red = settings.fetch_normalized("color", |value| {
+      from_str_radix::<u32>(value.trim_left_chars('#'), 16).unwrap().to_str()
+    });
+    assert_eq!(red, Some(0xff0000u32));
+
+    let missing: Option<u32> = settings.fetch_normalized("background", |value| value.to_string());
+    assert_eq!(missing, None)
+  }
+
+  #[test]
+  fn test_save_overrides() {
+    let dir = TempDir::new("knob").unwrap();
+    let path = dir.path().join("settings.conf");
+
+    let mut defaults = Settings::new();
+    defaults.set("port", 8080i);
+    defaults.set("ip", "127.0.0.1");
+
+    let mut settings = defaults.clone();
+    settings.set("port", 9090i);
+    settings.set("environment", "production");
+    assert!(settings.save_overrides(&path, &defaults).is_ok());
+
+    let contents = File::open(&path).read_to_str().unwrap();
+    assert_eq!(contents.as_slice(), "environment=production\nport=9090\n")
+  }
+
+  #[test]
+  fn test_fetch_f64() {
+    let mut settings = Settings::new();
+    settings.set("gain", "0.8");
+    settings.set("sample_rate", 44100i);
+    settings.set("frequency", "1e6");
+    settings.set("pitch", "440.5 Hz");
+    assert_eq!(settings.fetch_f64("gain"), Some(0.8));
+    assert_eq!(settings.fetch_f64("sample_rate"), Some(44100.0));
+    assert_eq!(settings.fetch_f64("frequency"), Some(1000000.0));
+    assert_eq!(settings.fetch_f64_unit("pitch", "Hz"), Some(440.5));
+    assert_eq!(settings.fetch_f64_unit("gain", "Hz"), Some(0.8));
+    assert_eq!(settings.fetch_f64("missing"), None)
+  }
+
+  #[test]
+  #[should_fail]
+  fn test_fetch_f64_unit_invalid() {
+    let mut settings = Settings::new();
+    settings.set("pitch", "440.5 dB");
+    settings.fetch_f64_unit("pitch", "Hz");
+  }
+
+  #[test]
+  fn test_add_options() {
+    let mut settings = Settings::new();
+    settings.add_options([
+      OptSpec { short: "p", long: "port", desc: "the port to bind to", hint: "4000",
+                required: true, kind: ValueKind },
+      OptSpec { short: "v", long: "verbose", desc: "be verbose", hint: "",
+                required: false, kind: FlagKind },
+      OptSpec { short: "", long: "host", desc: "the hosts to connect to", hint: "HOST",
+                required: false, kind: MultiKind },
+    ]);
+
+    let args = vec!("myprog".to_string(), "--port".to_string(), "4000".to_string(), "-v".to_string(),
+                    "--host".to_string(), "a".to_string(), "--host".to_string(), "b".to_string());
+    assert!(settings.load_args(args).is_ok());
+    assert_eq!(settings.fetch_int("port"), Some(4000));
+    assert_eq!(settings.fetch_bool("verbose"), Some(true));
+    assert_eq!(settings.fetch_list("host"), Some(vec!("a".to_string(), "b".to_string())));
+
+    assert_eq!(settings.load_args(vec!("myprog".to_string())), Err(OptionMissing("port".to_string())))
+  }
+
+  #[test]
+  fn test_peek() {
+    let mut settings = Settings::new();
+    settings.set("script", "echo hello");
+    assert_eq!(settings.peek("script"), Some("echo hello"));
+    assert_eq!(settings.peek("script").map(|script| script.len()), Some(10));
+    assert_eq!(settings.peek("missing"), None);
+
+    settings.set("script", "echo bye");
+    assert_eq!(settings.peek("script"), Some("echo bye"))
+  }
+
+  #[test]
+  fn test_into_map() {
+    let mut settings = Settings::new();
+    settings.opt(optopt("p", "port", "the port to bind to", "4000"));
+    settings.set("port", 8080i);
+    settings.set("host", "localhost");
+
+    let map = settings.into_map();
+    assert_eq!(map.len(), 2);
+    assert_eq!(map.find_equiv(&"port"), Some(&"8080".to_string()));
+    assert_eq!(map.find_equiv(&"host"), Some(&"localhost".to_string()))
+  }
+
+  #[test]
+  fn test_trim_values() {
+    let mut settings = Settings::new();
+    settings.set_trim_values(true);
+    settings.set("port", "3000   ");
+    settings.set("indent", "    ");
+    let args = vec!("host=  localhost ".to_string());
+    assert!(settings.load_assignments(args.as_slice()).is_ok());
+    assert_eq!(settings.fetch_int("port"), Some(3000));
+    assert_eq!(settings.get_raw("indent"), Some("".to_string()));
+    assert_eq!(settings.get_raw("host"), Some("localhost".to_string()));
+
+    settings.set_trim_values(false);
+    settings.set("indent", "    ");
+    assert_eq!(settings.get_raw("indent"), Some("    ".to_string()))
+  }
+
+  #[test]
+  fn test_load_args_short_multi() {
+    let mut settings = Settings::new();
+    settings.opt(optmulti("I", "", "a directory to include", "DIR"));
+    settings.add_options([
+      OptSpec { short: "L", long: "", desc: "a directory to link", hint: "DIR",
+                required: false, kind: MultiKind },
+    ]);
+
+    settings.opt(optopt("o", "", "the output file", "FILE"));
+    settings.opt(optflag("v", "", "print more"));
+
+    let args = vec!("myprog".to_string(), "-I".to_string(), "src".to_string(),
+                    "-I".to_string(), "lib".to_string(), "-L".to_string(), "target".to_string(),
+                    "-o".to_string(), "knob".to_string(), "-v".to_string());
+    assert!(settings.load_args(args).is_ok());
+    assert_eq!(settings.fetch_list("I"), Some(vec!("src".to_string(), "lib".to_string())));
+    assert_eq!(settings.fetch_list("L"), Some(vec!("target".to_string())));
+    assert_eq!(settings.get_raw("o"), Some("knob".to_string()));
+    assert_eq!(settings.fetch_bool("v"), Some(true));
+    assert!(!settings.contains(""))
+  }
+
+  #[test]
+  fn test_opt_default_below_set() {
+    let mut settings = Settings::new();
+    settings.opt_default(optopt("p", "port", "the port to bind to", "4000"), "4000");
+    settings.set("port", 9000i);
+    assert!(settings.load_args(vec!("myprog".to_string())).is_ok());
+    assert_eq!(settings.fetch_int("port"), Some(9000));
+
+    assert!(settings.load_json(r#"{"port": 9001}"#).is_ok());
+    assert!(settings.load_args(vec!("myprog".to_string())).is_ok());
+    assert_eq!(settings.fetch_int("port"), Some(9001))
+  }
+
+  #[test]
+  fn test_on_change_clone() {
+    let (sender, receiver) = channel();
+    let mut settings = Settings::new();
+    settings.on_change(sender);
+
+    let mut copy = settings.clone();
+    copy.set("port", 8080i);
+    assert!(receiver.try_recv().is_err());
+
+    settings.set("port", 9090i);
+    assert_eq!(receiver.recv(), ("port".to_string(), "9090".to_string()))
+  }
+
+  #[test]
+  fn test_on_change_expand() {
+    os::setenv("KNOB_OBSERVER_TEST_HOST", "example.com");
+    let (sender, receiver) = channel();
+    let mut settings = Settings::new();
+    settings.set("host", "${KNOB_OBSERVER_TEST_HOST}");
+    settings.set("url", "http://${host}/");
+    settings.set("port", 8080i);
+    settings.on_change(sender);
+
+    settings.expand_env(true);
+    assert_eq!(receiver.recv(), ("host".to_string(), "example.com".to_string()));
+    assert!(receiver.try_recv().is_err());
+
+    assert!(settings.interpolate().is_ok());
+    assert_eq!(receiver.recv(), ("url".to_string(), "http://example.com/".to_string()));
+    assert!(receiver.try_recv().is_err());
+    assert_eq!(settings.source_of("url"), Some(ManualSource));
+    os::unsetenv("KNOB_OBSERVER_TEST_HOST")
+  }
+
   #[test]
   fn test_goes_up_to_eleven() {
     let mut settings = Settings::new();
@@ -0,0 +1,137 @@
+//! A parser for the flat subset of TOML supported by `Settings::load_toml`.
+
+use super::{Error, InvalidToml, UnsupportedValue};
+
+/// Parse a TOML document into pairs of keys and raw values. Keys in a
+/// `[section]` are prefixed with the section name and a dot.
+pub fn parse(text: &str) -> Result<Vec<(String, String)>, Error> {
+  let mut pairs = Vec::new();
+  let mut section = String::new();
+  for (index, line) in text.lines().enumerate() {
+    let number = index + 1;
+    let line = strip_comment(line).trim();
+    if line.is_empty() {
+      continue
+    }
+    if line.starts_with("[[") {
+      return Err(UnsupportedValue(line.trim_chars(&['[', ']']).trim().to_string()))
+    }
+    if line.starts_with("[") {
+      if !line.ends_with("]") {
+        return Err(syntax_error(number, "unterminated table header"))
+      }
+      section = line.slice(1, line.len() - 1).trim().to_string();
+      continue
+    }
+    let (key, value) = match line.find('=') {
+      Some(position) => { (line.slice_to(position).trim(), line.slice_from(position + 1).trim()) },
+      None => { return Err(syntax_error(number, "expected a key = value pair")) }
+    };
+    let key = match parse_key(key) {
+      Some(key) => { key },
+      None => { return Err(syntax_error(number, "invalid key")) }
+    };
+    let key = if section.is_empty() { key } else { format!("{}.{}", section, key) };
+    let value = match parse_value(value) {
+      Ok(Some(value)) => { value },
+      Ok(None) => { return Err(UnsupportedValue(key)) },
+      Err(message) => { return Err(syntax_error(number, message)) }
+    };
+    pairs.push((key, value));
+  }
+  Ok(pairs)
+}
+
+fn syntax_error(number: uint, message: &str) -> Error {
+  InvalidToml(format!("line {}: {}", number, message))
+}
+
+/// Remove a trailing comment, ignoring `#` within strings.
+pub fn strip_comment<'a>(line: &'a str) -> &'a str {
+  let mut quote = None;
+  let mut escaped = false;
+  for (position, c) in line.char_indices() {
+    match quote {
+      Some(delimiter) => {
+        if escaped {
+          escaped = false;
+        } else if c == '\\' && delimiter == '"' {
+          escaped = true;
+        } else if c == delimiter {
+          quote = None;
+        }
+      },
+      None => {
+        if c == '#' {
+          return line.slice_to(position)
+        }
+        if c == '"' || c == '\'' {
+          quote = Some(c);
+        }
+      }
+    }
+  }
+  line
+}
+
+fn parse_key(key: &str) -> Option<String> {
+  if key.starts_with("\"") {
+    parse_basic_string(key)
+  } else if !key.is_empty() && key.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '-' || c == '.') {
+    Some(key.to_string())
+  } else {
+    None
+  }
+}
+
+/// Parse a value into its raw string. Returns `Ok(None)` for arrays and inline
+/// tables, which are not supported.
+fn parse_value(value: &str) -> Result<Option<String>, &'static str> {
+  if value.is_empty() {
+    return Err("missing value")
+  }
+  match value.char_at(0) {
+    '[' | '{' => { Ok(None) },
+    '"' => {
+      match parse_basic_string(value) {
+        Some(string) => { Ok(Some(string)) },
+        None => { Err("invalid string") }
+      }
+    },
+    '\'' => {
+      if value.len() >= 2 && value.ends_with("'") && !value.starts_with("'''") {
+        Ok(Some(value.slice(1, value.len() - 1).to_string()))
+      } else {
+        Err("invalid string")
+      }
+    },
+    '0'..'9' | '+' | '-' => { Ok(Some(value.chars().filter(|&c| c != '_').collect())) },
+    _ => { Ok(Some(value.to_string())) }
+  }
+}
+
+/// Parse a double-quoted string with escapes, as used for TOML basic strings.
+pub fn parse_basic_string(value: &str) -> Option<String> {
+  if value.len() < 2 || !value.ends_with("\"") {
+    return None
+  }
+  let mut result = String::new();
+  let mut chars = value.slice(1, value.len() - 1).chars();
+  loop {
+    match chars.next() {
+      Some('\\') => {
+        match chars.next() {
+          Some('n') => { result.push_char('\n') },
+          Some('t') => { result.push_char('\t') },
+          Some('r') => { result.push_char('\r') },
+          Some('"') => { result.push_char('"') },
+          Some('\\') => { result.push_char('\\') },
+          _ => { return None }
+        }
+      },
+      Some('"') => { return None },
+      Some(c) => { result.push_char(c) },
+      None => { return Some(result) }
+    }
+  }
+}
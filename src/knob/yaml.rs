@@ -0,0 +1,149 @@
+//! A parser for the flat subset of YAML supported by `Settings::load_yaml`.
+
+use super::{Error, InvalidYaml, UnsupportedValue};
+use super::toml::parse_basic_string;
+
+/// Parse a YAML mapping into pairs of keys and raw values. Keys of a nested
+/// mapping are prefixed with the parent key and a dot. Only one level of
+/// nesting is supported.
+pub fn parse(text: &str) -> Result<Vec<(String, String)>, Error> {
+  let mut pairs = Vec::new();
+  let mut parent: Option<String> = None;
+  let mut child_indent = None;
+  for (index, line) in text.lines().enumerate() {
+    let number = index + 1;
+    let line = strip_comment(line).trim_right();
+    let content = line.trim_left();
+    if content.is_empty() {
+      continue
+    }
+    if line.starts_with("\t") {
+      return Err(syntax_error(number, "tabs are not allowed for indentation"))
+    }
+    let indent = line.len() - content.len();
+
+    if indent == 0 {
+      if content == "---" || content == "..." {
+        continue
+      }
+      if content.starts_with("-") {
+        return Err(syntax_error(number, "expected a mapping"))
+      }
+      match parent.take() {
+        Some(key) => { if child_indent.is_none() { pairs.push((key, String::new())) } },
+        None => {}
+      }
+      child_indent = None;
+      let (key, value) = try!(split_pair(number, content));
+      if value.is_empty() {
+        parent = Some(key);
+      } else {
+        let value = try!(parse_scalar(number, key.as_slice(), value));
+        pairs.push((key, value));
+      }
+      continue
+    }
+
+    let parent_key = match parent {
+      Some(ref key) => { key.clone() },
+      None => { return Err(syntax_error(number, "unexpected indentation")) }
+    };
+    if content.starts_with("-") {
+      return Err(UnsupportedValue(parent_key))
+    }
+    match child_indent {
+      Some(expected) if expected != indent => {
+        return Err(syntax_error(number, "inconsistent indentation"))
+      },
+      _ => { child_indent = Some(indent) }
+    }
+    let (key, value) = try!(split_pair(number, content));
+    let key = format!("{}.{}", parent_key, key);
+    if value.is_empty() {
+      return Err(UnsupportedValue(key))
+    }
+    let value = try!(parse_scalar(number, key.as_slice(), value));
+    pairs.push((key, value));
+  }
+  match parent {
+    Some(key) => { if child_indent.is_none() { pairs.push((key, String::new())) } },
+    None => {}
+  }
+  Ok(pairs)
+}
+
+fn syntax_error(number: uint, message: &str) -> Error {
+  InvalidYaml(format!("line {}: {}", number, message))
+}
+
+/// Remove a trailing comment, which starts with a `#` at the beginning of the
+/// line or after whitespace, ignoring `#` within quoted strings.
+fn strip_comment<'a>(line: &'a str) -> &'a str {
+  let mut quote = None;
+  let mut previous = ' ';
+  for (position, c) in line.char_indices() {
+    match quote {
+      Some(delimiter) => {
+        if c == delimiter && !(delimiter == '"' && previous == '\\') {
+          quote = None;
+        }
+      },
+      None => {
+        if c == '#' && previous.is_whitespace() {
+          return line.slice_to(position)
+        }
+        if (c == '"' || c == '\'') && (previous.is_whitespace() || previous == ':') {
+          quote = Some(c);
+        }
+      }
+    }
+    previous = c;
+  }
+  line
+}
+
+/// Split a `key: value` line into the key and the raw value, which is empty
+/// if the key starts a nested mapping.
+fn split_pair<'a>(number: uint, content: &'a str) -> Result<(String, &'a str), Error> {
+  let (key, rest) = if content.starts_with("\"") || content.starts_with("'") {
+    let quote = content.char_at(0);
+    let end = match content.slice_from(1).find(quote) {
+      Some(end) => { end + 1 },
+      None => { return Err(syntax_error(number, "invalid key")) }
+    };
+    (content.slice(1, end).to_string(), content.slice_from(end + 1).trim_left())
+  } else {
+    let end = match content.find_str(": ") {
+      Some(end) => { end },
+      None if content.ends_with(":") => { content.len() - 1 },
+      None => { return Err(syntax_error(number, "expected a key: value pair")) }
+    };
+    (content.slice_to(end).trim_right().to_string(), content.slice_from(end))
+  };
+  if key.is_empty() || !rest.starts_with(":") {
+    return Err(syntax_error(number, "expected a key: value pair"))
+  }
+  Ok((key, rest.slice_from(1).trim()))
+}
+
+/// Parse a scalar into its raw string. Flow collections, block scalars,
+/// anchors, aliases and tags are not supported.
+fn parse_scalar(number: uint, key: &str, value: &str) -> Result<String, Error> {
+  match value.char_at(0) {
+    '[' | '{' | '|' | '>' | '&' | '*' | '!' => { Err(UnsupportedValue(key.to_string())) },
+    '"' => {
+      match parse_basic_string(value) {
+        Some(string) => { Ok(string) },
+        None => { Err(syntax_error(number, "invalid string")) }
+      }
+    },
+    '\'' => {
+      if value.len() >= 2 && value.ends_with("'") {
+        Ok(value.slice(1, value.len() - 1).replace("''", "'"))
+      } else {
+        Err(syntax_error(number, "invalid string"))
+      }
+    },
+    _ => { Ok(value.to_string()) }
+  }
+}